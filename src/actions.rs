@@ -0,0 +1,347 @@
+//! Moving duplicates away and replacing them with links, without ever losing a file on the way.
+
+use std::ffi::OsString;
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{self, ErrorKind};
+use std::path::{Component, Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::escape::escape_path;
+use crate::verify::same_contents;
+
+/// How a duplicate is replaced by a link to the kept file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LinkKind {
+    /// Whatever works best: a reflink, then a hardlink, then a symlink
+    Auto,
+    /// Copy-on-write clone, an independent file sharing the kept file's blocks (btrfs, XFS)
+    Reflink,
+    /// Another name for the kept file itself, same filesystem only
+    Hard,
+    /// A relative symbolic link, works across filesystems
+    Symbolic,
+}
+
+impl fmt::Display for LinkKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            LinkKind::Auto => "link",
+            LinkKind::Reflink => "reflink",
+            LinkKind::Hard => "hardlink",
+            LinkKind::Symbolic => "symlink",
+        };
+        f.write_str(name)
+    }
+}
+
+/// Move a file to `destination`, creating its parent directories. Falls back to
+/// copy, verify and unlink when the destination is on another filesystem.
+/// Never replaces anything already at `destination`, even something that appears there
+/// while the file is being moved.
+pub fn move_file(source: &Path, destination: &Path) -> io::Result<()> {
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    match rename_no_replace(source, destination) {
+        Ok(()) => Ok(()),
+        Err(e) if e.kind() == ErrorKind::CrossesDevices => copy_then_unlink(source, destination),
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => Err(io::Error::new(
+            ErrorKind::AlreadyExists,
+            format!("{} already exists", escape_path(destination)),
+        )),
+        Err(e) => Err(e),
+    }
+}
+
+// Rename a file, failing instead of replacing whatever is at `destination`. Linux does that in
+// a single call; elsewhere, or on filesystems that don't support it, the file gets its new
+// name as a hard link, which can't replace anything either, and then loses the old one.
+// Filesystems without hard links get a copy instead.
+fn rename_no_replace(source: &Path, destination: &Path) -> io::Result<()> {
+    #[cfg(target_os = "linux")]
+    {
+        use std::ffi::CString;
+        use std::os::unix::ffi::OsStrExt;
+
+        let to_c = |path: &Path| CString::new(path.as_os_str().as_bytes()).map_err(io::Error::other);
+        let (from, to) = (to_c(source)?, to_c(destination)?);
+        // SAFETY: both strings are NUL-terminated and live until the call returns
+        let renamed = unsafe {
+            libc::renameat2(libc::AT_FDCWD, from.as_ptr(), libc::AT_FDCWD, to.as_ptr(), libc::RENAME_NOREPLACE)
+        };
+        if renamed == 0 {
+            return Ok(());
+        }
+        let error = io::Error::last_os_error();
+        if !matches!(error.raw_os_error(), Some(libc::EINVAL | libc::ENOSYS)) {
+            return Err(error);
+        }
+    }
+    match fs::hard_link(source, destination) {
+        Ok(()) => fs::remove_file(source),
+        Err(e) if matches!(e.kind(), ErrorKind::AlreadyExists | ErrorKind::CrossesDevices | ErrorKind::NotFound) => Err(e),
+        Err(_) => copy_then_unlink(source, destination),
+    }
+}
+
+// Copy a file across filesystems, make sure the copy is intact, and only then remove the original
+fn copy_then_unlink(source: &Path, destination: &Path) -> io::Result<()> {
+    // Only ever write to a file made here, so nothing that was already there is truncated
+    let mut copy = File::options().write(true).create_new(true).open(destination)?;
+    let copied = (|| {
+        let metadata = fs::metadata(source)?;
+        io::copy(&mut File::open(source)?, &mut copy)?;
+        // Keep the original modification time so the moved file still sorts and compares the same
+        copy.set_modified(metadata.modified()?)?;
+        copy.set_permissions(metadata.permissions())?;
+
+        if !same_contents(source, destination)? {
+            return Err(io::Error::other("copy does not match the original"));
+        }
+        Ok(())
+    })();
+
+    // Leave the original alone, and don't leave a half-made copy behind, if anything went wrong
+    if let Err(e) = copied {
+        let _ = fs::remove_file(destination);
+        return Err(e);
+    }
+    fs::remove_file(source)
+}
+
+// A name next to `path` that nothing else is using, to build its replacement under
+fn temporary_sibling(path: &Path, attempt: u32) -> PathBuf {
+    let mut name = OsString::from(".");
+    name.push(path.file_name().unwrap_or_default());
+    name.push(format!(".dupchecker-{}-{}", std::process::id(), attempt));
+    path.with_file_name(name)
+}
+
+// Build a replacement with `create` under a temporary name and rename it over `path`, so the
+// path always refers to either the old file or its replacement and is never missing
+fn replace_atomically(path: &Path, create: impl Fn(&Path) -> io::Result<()>) -> io::Result<()> {
+    for attempt in 0..100 {
+        let temporary = temporary_sibling(path, attempt);
+        match create(&temporary) {
+            Ok(()) => {}
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                let _ = fs::remove_file(&temporary);
+                return Err(e);
+            }
+        }
+        if let Err(e) = fs::rename(&temporary, path) {
+            let _ = fs::remove_file(&temporary);
+            return Err(e);
+        }
+        return Ok(());
+    }
+    Err(io::Error::new(ErrorKind::AlreadyExists, "no free temporary name"))
+}
+
+// Clone `source` into a new file at `destination` with FICLONE
+#[cfg(target_os = "linux")]
+fn reflink(source: &Path, destination: &Path) -> io::Result<()> {
+    use std::os::unix::io::AsRawFd;
+
+    let source_file = File::open(source)?;
+    let destination_file = File::options().write(true).create_new(true).open(destination)?;
+    // SAFETY: both descriptors are open for as long as the call runs
+    if unsafe { libc::ioctl(destination_file.as_raw_fd(), libc::FICLONE, source_file.as_raw_fd()) } != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+// Other systems have no FICLONE, so `auto` falls back to a hardlink there
+#[cfg(not(target_os = "linux"))]
+fn reflink(_source: &Path, _destination: &Path) -> io::Result<()> {
+    Err(io::Error::new(ErrorKind::Unsupported, "reflinks are only supported on Linux"))
+}
+
+// The folder a file is in. A bare file name has an empty parent rather than none, and that
+// means the current folder too.
+pub(crate) fn parent_dir(path: &Path) -> &Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => Path::new("."),
+    }
+}
+
+#[cfg(unix)]
+fn symlink(target: &Path, link: &Path) -> io::Result<()> {
+    std::os::unix::fs::symlink(target, link)
+}
+
+// Symbolic links elsewhere need privileges most users don't have, so `auto` stops at hardlinks
+#[cfg(not(unix))]
+fn symlink(_target: &Path, _link: &Path) -> io::Result<()> {
+    Err(io::Error::new(ErrorKind::Unsupported, "symbolic links are only supported on Unix-like systems"))
+}
+
+// The path of `target` as seen from inside `from_dir`, both absolute
+fn relative_path(from_dir: &Path, target: &Path) -> PathBuf {
+    let from: Vec<Component> = from_dir.components().collect();
+    let to: Vec<Component> = target.components().collect();
+    let common = from.iter().zip(&to).take_while(|(a, b)| a == b).count();
+
+    let mut relative = PathBuf::new();
+    for _ in common..from.len() {
+        relative.push("..");
+    }
+    for component in &to[common..] {
+        relative.push(component);
+    }
+    relative
+}
+
+/// Replace `duplicate` with a link to `keep`, returning the kind of link that was made.
+/// Reflinks keep the duplicate's permissions and modification time, since they stay a separate file.
+pub fn link_file(keep: &Path, duplicate: &Path, kind: LinkKind) -> io::Result<LinkKind> {
+    let attempts: &[LinkKind] = match kind {
+        LinkKind::Auto => &[LinkKind::Reflink, LinkKind::Hard, LinkKind::Symbolic],
+        LinkKind::Reflink => &[LinkKind::Reflink],
+        LinkKind::Hard => &[LinkKind::Hard],
+        LinkKind::Symbolic => &[LinkKind::Symbolic],
+    };
+
+    let mut last_error = None;
+    for &attempt in attempts {
+        let linked = match attempt {
+            LinkKind::Reflink => {
+                let metadata = fs::metadata(duplicate)?;
+                replace_atomically(duplicate, |temporary| {
+                    reflink(keep, temporary)?;
+                    // Set the time while the clone is still writable, then the duplicate's permissions
+                    File::options().write(true).open(temporary)?.set_modified(metadata.modified()?)?;
+                    fs::set_permissions(temporary, metadata.permissions())
+                })
+            }
+            LinkKind::Hard => replace_atomically(duplicate, |temporary| fs::hard_link(keep, temporary)),
+            LinkKind::Symbolic => {
+                let target = fs::canonicalize(keep)?;
+                let directory = fs::canonicalize(parent_dir(duplicate))?;
+                let relative = relative_path(&directory, &target);
+                replace_atomically(duplicate, |temporary| symlink(&relative, temporary))
+            }
+            LinkKind::Auto => unreachable!("auto is expanded into concrete kinds above"),
+        };
+        match linked {
+            Ok(()) => return Ok(attempt),
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.expect("at least one kind of link was tried"))
+}
+
+/// Turn a link made by `link_file` back into a file of its own: a copy of `source` with
+/// the modification time the duplicate had before it was linked
+pub fn unlink_file(path: &Path, source: &Path, modified: Option<SystemTime>) -> io::Result<()> {
+    let permissions = fs::metadata(source)?.permissions();
+    replace_atomically(path, |temporary| {
+        let mut copy = File::options().write(true).create_new(true).open(temporary)?;
+        io::copy(&mut File::open(source)?, &mut copy)?;
+        if let Some(modified) = modified {
+            copy.set_modified(modified)?;
+        }
+        fs::set_permissions(temporary, permissions.clone())
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn bare_names_are_in_the_current_folder() {
+        assert_eq!(parent_dir(Path::new("a.jpg")), Path::new("."));
+        assert_eq!(parent_dir(Path::new("photos/a.jpg")), Path::new("photos"));
+        assert_eq!(parent_dir(Path::new("/a.jpg")), Path::new("/"));
+        assert!(fs::canonicalize(parent_dir(Path::new("a.jpg"))).is_ok());
+    }
+
+    #[test]
+    fn moving_creates_the_destination_folders() {
+        let dir = TempDir::new();
+        let source = dir.write("a.jpg", b"picture");
+        let destination = dir.path().join("quarantine/deep/a.jpg");
+        move_file(&source, &destination).unwrap();
+        assert!(!source.exists());
+        assert_eq!(fs::read(&destination).unwrap(), b"picture");
+    }
+
+    #[test]
+    fn moving_never_replaces_a_file() {
+        let dir = TempDir::new();
+        let source = dir.write("a.jpg", b"picture");
+        let destination = dir.write("q/a.jpg", b"someone else's");
+        let error = move_file(&source, &destination).unwrap_err();
+        assert_eq!(error.kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&source).unwrap(), b"picture");
+        assert_eq!(fs::read(&destination).unwrap(), b"someone else's");
+    }
+
+    #[test]
+    fn copying_never_truncates_or_removes_a_file() {
+        // What a move across filesystems does
+        let dir = TempDir::new();
+        let source = dir.write("a.jpg", b"picture");
+        let destination = dir.write("q/a.jpg", b"someone else's");
+        assert_eq!(copy_then_unlink(&source, &destination).unwrap_err().kind(), ErrorKind::AlreadyExists);
+        assert_eq!(fs::read(&source).unwrap(), b"picture");
+        assert_eq!(fs::read(&destination).unwrap(), b"someone else's");
+
+        let destination = dir.path().join("q/b.jpg");
+        copy_then_unlink(&source, &destination).unwrap();
+        assert!(!source.exists());
+        assert_eq!(fs::read(&destination).unwrap(), b"picture");
+    }
+
+    #[test]
+    fn relative_paths_climb_out_of_the_link_folder() {
+        assert_eq!(relative_path(Path::new("/photos/a"), Path::new("/photos/a/x.jpg")), Path::new("x.jpg"));
+        assert_eq!(relative_path(Path::new("/photos/a/b"), Path::new("/photos/c/x.jpg")), Path::new("../../c/x.jpg"));
+        assert_eq!(relative_path(Path::new("/"), Path::new("/x.jpg")), Path::new("x.jpg"));
+    }
+
+    #[test]
+    fn failed_replacements_leave_the_file_alone() {
+        let dir = TempDir::new();
+        let path = dir.write("a.jpg", b"picture");
+        let error = replace_atomically(&path, |temporary| {
+            fs::write(temporary, b"half written")?;
+            Err(io::Error::other("disk full"))
+        });
+        assert_eq!(error.unwrap_err().to_string(), "disk full");
+        assert_eq!(fs::read(&path).unwrap(), b"picture");
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn links_replace_duplicates_and_can_be_undone() {
+        use crate::scan::FileStamp;
+
+        let dir = TempDir::new();
+        let keep = dir.write("keep/a.jpg", b"picture");
+        let hard = dir.write("b.jpg", b"picture");
+        let symbolic = dir.write("copies/c.jpg", b"picture");
+
+        assert_eq!(link_file(&keep, &hard, LinkKind::Hard).unwrap(), LinkKind::Hard);
+        assert_eq!(FileStamp::of(&hard).unwrap().file_id(), FileStamp::of(&keep).unwrap().file_id());
+        assert_eq!(link_file(&keep, &symbolic, LinkKind::Symbolic).unwrap(), LinkKind::Symbolic);
+        assert_eq!(fs::read_link(&symbolic).unwrap(), Path::new("../keep/a.jpg"));
+        assert_eq!(fs::read(&symbolic).unwrap(), b"picture");
+
+        // Undoing leaves a copy of its own, with the time it had before
+        let modified = std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000);
+        unlink_file(&symbolic, &keep, Some(modified)).unwrap();
+        let metadata = fs::symlink_metadata(&symbolic).unwrap();
+        assert!(metadata.is_file());
+        assert_eq!(metadata.modified().unwrap(), modified);
+        assert_ne!(FileStamp::of(&symbolic).unwrap().file_id(), FileStamp::of(&keep).unwrap().file_id());
+    }
+}
@@ -0,0 +1,265 @@
+//! Reading back a report someone has reviewed, and planning the removals it asks for.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use rayon::prelude::*;
+
+use crate::escape::{escape_path, unescape_path};
+use crate::hasher::HashAlgorithm;
+use crate::plan::{Action, ActionPlan, GroupPlan, PlannedFile};
+use crate::report::{parse_delimited, parse_timestamp, Report, REPORT_FORMAT, REPORT_VERSION};
+use crate::scan::{calculate_image_hash, FileStamp};
+
+// A file as a reviewed report lists it
+struct ReviewedFile {
+    path: PathBuf,
+    size: u64,
+    modified: Option<SystemTime>,
+    keep: bool,
+    // Whether the file lies in a reference folder, and so must never be removed
+    reference: bool,
+}
+
+struct ReviewedGroup {
+    hash: String,
+    files: Vec<ReviewedFile>,
+}
+
+/// The decisions read back from a report after someone has been through it
+pub struct ReviewedReport {
+    // The scanned folders, which quarantined paths are made relative to, and the reference
+    // folders nothing may be removed from. CSV and TSV don't record either, only which files
+    // are in a reference folder.
+    roots: Vec<PathBuf>,
+    references: Vec<PathBuf>,
+    groups: Vec<ReviewedGroup>,
+}
+
+fn parse_modified(modified: &str) -> Result<Option<SystemTime>, String> {
+    match modified.trim() {
+        "" => Ok(None),
+        modified => parse_timestamp(modified).map(Some).ok_or_else(|| format!("bad modification time '{}'", modified)),
+    }
+}
+
+fn from_json(text: &str) -> Result<ReviewedReport, Box<dyn std::error::Error>> {
+    let report: Report = serde_json::from_str(text)?;
+    if report.format != REPORT_FORMAT {
+        return Err(format!("not a dupchecker report (format '{}')", report.format).into());
+    }
+    if report.version > REPORT_VERSION {
+        return Err(format!("report version {} is newer than this dupchecker understands", report.version).into());
+    }
+
+    let mut groups = Vec::new();
+    for group in report.groups {
+        let mut files = Vec::new();
+        for file in group.files {
+            // Version 1 reports wrote paths as they were, which escaping leaves alone unless they hold a backslash
+            let path = if report.version < 2 { PathBuf::from(&file.path) } else { unescape_path(&file.path)? };
+            let modified = match &file.modified {
+                Some(modified) => parse_modified(modified).map_err(|e| format!("{}: {}", file.path, e))?,
+                None => None,
+            };
+            files.push(ReviewedFile { path, size: file.size, modified, keep: file.keep, reference: file.reference });
+        }
+        groups.push(ReviewedGroup { hash: group.hash, files });
+    }
+    let roots = if report.version < 2 {
+        report.roots.iter().map(PathBuf::from).collect()
+    } else {
+        report.roots.iter().map(|root| unescape_path(root)).collect::<Result<_, _>>()?
+    };
+    let references = report.references.iter().map(|root| unescape_path(root)).collect::<Result<_, _>>()?;
+    Ok(ReviewedReport { roots, references, groups })
+}
+
+fn from_delimited(text: &str, delimiter: char) -> Result<ReviewedReport, Box<dyn std::error::Error>> {
+    let mut rows = parse_delimited(text, delimiter)?.into_iter();
+    let header = rows.next().ok_or("the report is empty")?;
+    // Find columns by name, so a spreadsheet that reordered or added columns still works
+    let column = |name: &str| {
+        header.iter().position(|field| field.trim() == name).ok_or_else(|| format!("the report has no '{}' column", name))
+    };
+    let (group_column, hash_column, size_column) = (column("group")?, column("hash")?, column("size")?);
+    let (path_column, modified_column, action_column) = (column("path")?, column("modified")?, column("action")?);
+    // Reports written before reference folders existed have no such column
+    let reference_column = column("reference").ok();
+
+    let mut group_ids: Vec<String> = Vec::new();
+    let mut groups: Vec<ReviewedGroup> = Vec::new();
+    for (number, row) in rows.enumerate() {
+        // Rows are numbered as a spreadsheet shows them, the header being row 1
+        let row_error = |message: String| format!("row {}: {}", number + 2, message);
+        if row.iter().all(|field| field.trim().is_empty()) {
+            continue;
+        }
+        let field = |index: usize| row.get(index).map(String::as_str).unwrap_or("");
+
+        let keep = match field(action_column).trim().to_lowercase().as_str() {
+            "keep" => true,
+            "remove" => false,
+            other => return Err(row_error(format!("action must be keep or remove, not '{}'", other)).into()),
+        };
+        let size = field(size_column).trim().parse::<u64>().map_err(|e| row_error(format!("bad size: {}", e)))?;
+        let modified = parse_modified(field(modified_column)).map_err(row_error)?;
+        let path = unescape_path(field(path_column)).map_err(row_error)?;
+        let reference = match reference_column.map(|index| field(index).trim().to_lowercase()).as_deref() {
+            None | Some("" | "false") => false,
+            Some("true") => true,
+            Some(other) => return Err(row_error(format!("reference must be true or false, not '{}'", other)).into()),
+        };
+        let file = ReviewedFile { path, size, modified, keep, reference };
+
+        let group_id = field(group_column).trim().to_string();
+        let hash = field(hash_column).trim();
+        match group_ids.iter().position(|id| *id == group_id) {
+            Some(index) if groups[index].hash != hash => {
+                return Err(row_error(format!("group {} has more than one hash", group_id)).into());
+            }
+            Some(index) => groups[index].files.push(file),
+            None => {
+                group_ids.push(group_id);
+                groups.push(ReviewedGroup { hash: hash.to_string(), files: vec![file] });
+            }
+        }
+    }
+    Ok(ReviewedReport { roots: Vec::new(), references: Vec::new(), groups })
+}
+
+/// Read a JSON, CSV or TSV report, telling them apart by their contents
+pub fn load_report(path: &Path) -> Result<ReviewedReport, Box<dyn std::error::Error>> {
+    let text = fs::read_to_string(path)?;
+    let header = text.lines().next().unwrap_or("");
+    let report = if text.trim_start().starts_with('{') {
+        from_json(&text)
+    } else if header.contains('\t') {
+        from_delimited(&text, '\t')
+    } else {
+        from_delimited(&text, ',')
+    };
+    report.map_err(|e| format!("{}: {}", escape_path(path), e).into())
+}
+
+// The content hash a group was keyed by, if it was keyed by one. Perceptual groups
+// are keyed by one member's perceptual hash, which the other members needn't share.
+fn content_algorithm(hash: &str) -> Option<HashAlgorithm> {
+    let (name, _) = hash.split_once(':')?;
+    HashAlgorithm::from_name(name)
+}
+
+// Make sure a file is still the one the report describes, returning how it looks now
+fn check_file(file: &ReviewedFile, hash: &str) -> Result<FileStamp, String> {
+    let stamp = FileStamp::of(&file.path).map_err(|e| format!("cannot stat it: {}", e))?;
+    if stamp.size != file.size {
+        return Err(format!("its size changed from {} to {} bytes", file.size, stamp.size));
+    }
+    if file.modified.is_some() && stamp.modified != file.modified {
+        return Err("it was modified after the report was written".to_string());
+    }
+    if let Some(algorithm) = content_algorithm(hash) {
+        let current = calculate_image_hash(&file.path, algorithm).map_err(|e| format!("cannot hash it: {}", e))?;
+        if format!("{}:{}", algorithm.name(), current) != hash {
+            return Err("its contents no longer match the report's hash".to_string());
+        }
+    }
+    Ok(stamp)
+}
+
+/// Turn a reviewed report into a plan, leaving out every file that no longer matches the report,
+/// every file in a reference folder and every group without a file left to keep. Returns the
+/// plan and every file left out of it, with the reason.
+pub fn plan_from_report(report: &ReviewedReport, action: Action) -> (ActionPlan, Vec<(PathBuf, String)>) {
+    let mut refused = Vec::new();
+    let mut groups = Vec::new();
+    for group in &report.groups {
+        // Hashing is the slow part, so check a group's files in parallel
+        let checked: Vec<Result<FileStamp, String>> =
+            group.files.par_iter().map(|file| check_file(file, &group.hash)).collect();
+
+        let mut keep = None;
+        let mut remove = Vec::new();
+        for (file, check) in group.files.iter().zip(checked) {
+            if file.reference && !file.keep {
+                refused.push((file.path.clone(), "it is in a reference folder".to_string()));
+                continue;
+            }
+            let stamp = match check {
+                Ok(stamp) => stamp,
+                Err(reason) => {
+                    refused.push((file.path.clone(), reason));
+                    continue;
+                }
+            };
+            let planned = PlannedFile { path: file.path.clone(), stamp };
+            if !file.keep {
+                remove.push(planned);
+            } else if keep.is_none() {
+                // Further files marked keep simply stay where they are
+                keep = Some(planned);
+            }
+        }
+
+        match keep {
+            Some(keep) if !remove.is_empty() => groups.push(GroupPlan { hash: group.hash.clone(), keep, remove }),
+            Some(_) => {}
+            None => {
+                for file in remove {
+                    refused.push((file.path, "no file marked keep in its group is still intact".to_string()));
+                }
+            }
+        }
+    }
+
+    let compare_contents = groups.iter().all(|group| content_algorithm(&group.hash).is_some());
+    let plan = ActionPlan {
+        action,
+        roots: report.roots.clone(),
+        references: report.references.clone(),
+        groups,
+        compare_contents,
+    };
+    (plan, refused)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn only_intact_files_make_it_into_the_plan() {
+        let dir = TempDir::new();
+        let keep = dir.write("keep.jpg", b"picture");
+        let copy = dir.write("copy.jpg", b"picture");
+        let edited = dir.write("edited.jpg", b"picture");
+        let reference = dir.write("reference.jpg", b"picture");
+        let orphan = dir.write("orphan.jpg", b"other picture");
+        let hash = format!("sha256:{}", calculate_image_hash(&keep, HashAlgorithm::Sha256).unwrap());
+        fs::write(&edited, b"edited picture").unwrap();
+
+        let row = |group: u32, hash: &str, size: usize, path: &Path, action: &str, reference: bool| {
+            format!("{},{},{},{},,{},{}\n", group, hash, size, escape_path(path), action, reference)
+        };
+        let mut csv = "group,hash,size,path,modified,action,reference\n".to_string();
+        csv += &row(1, &hash, 7, &keep, "keep", false);
+        csv += &row(1, &hash, 7, &copy, "remove", false);
+        csv += &row(1, &hash, 7, &edited, "remove", false);
+        csv += &row(1, &hash, 7, &reference, "remove", true);
+        csv += &row(2, "phash:0", 13, &dir.path().join("gone.jpg"), "keep", false);
+        csv += &row(2, "phash:0", 13, &orphan, "remove", false);
+        let path = dir.write("report.csv", csv.as_bytes());
+
+        let (plan, refused) = plan_from_report(&load_report(&path).unwrap(), Action::Delete);
+        assert_eq!(plan.groups.len(), 1);
+        assert_eq!(plan.groups[0].keep.path, keep);
+        let removed: Vec<&Path> = plan.groups[0].remove.iter().map(|file| file.path.as_path()).collect();
+        assert_eq!(removed, [copy.as_path()]);
+        assert!(plan.compare_contents);
+
+        let refused: Vec<&Path> = refused.iter().map(|(path, _)| path.as_path()).collect();
+        assert_eq!(refused, [edited.as_path(), reference.as_path(), &dir.path().join("gone.jpg"), orphan.as_path()]);
+    }
+}
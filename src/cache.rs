@@ -0,0 +1,162 @@
+//! The SQLite cache that keeps file hashes between scans.
+
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::escape::{escape_path, unescape_path};
+use crate::scan::FileStamp;
+
+/// Hashes from earlier scans, keyed by path and by what the file looked like when it was hashed.
+/// Every entry also records the algorithm that produced it, so hashes are never mixed across algorithms.
+/// Paths are stored canonical, so entries don't depend on the folder a scan ran from, and escaped
+/// by escape_path, so names that aren't valid UTF-8 are cached as well.
+pub struct HashCache {
+    connection: Connection,
+}
+
+/// Where the cache lives unless told otherwise
+pub fn default_cache_path() -> Option<PathBuf> {
+    let cache_dir = match std::env::var_os("XDG_CACHE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".cache"),
+    };
+    Some(cache_dir.join("dupchecker").join("hashes.sqlite"))
+}
+
+// Modification time in nanoseconds since the epoch, as SQLite stores it
+fn modified_nanos(stamp: &FileStamp) -> Option<i64> {
+    let modified = stamp.modified?.duration_since(UNIX_EPOCH).ok()?;
+    i64::try_from(modified.as_nanos()).ok()
+}
+
+// The key a file is cached under: its canonical path, escaped
+fn cache_key(path: &Path) -> String {
+    match fs::canonicalize(path) {
+        Ok(canonical) => escape_path(&canonical),
+        Err(_) => escape_path(&std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf())),
+    }
+}
+
+impl HashCache {
+    /// Open the cache at `path`, creating it and its folder if need be
+    pub fn open(path: &Path) -> Result<HashCache, Box<dyn std::error::Error>> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        let connection = Connection::open(path)?;
+        connection.execute_batch(
+            "CREATE TABLE IF NOT EXISTS hashes (
+                path TEXT NOT NULL,
+                algorithm TEXT NOT NULL,
+                size INTEGER NOT NULL,
+                modified INTEGER NOT NULL,
+                inode INTEGER NOT NULL,
+                hash TEXT NOT NULL,
+                PRIMARY KEY (path, algorithm)
+            );",
+        )?;
+        Ok(HashCache { connection })
+    }
+
+    /// The cached hash of a file, if it was hashed with `algorithm` and hasn't changed since
+    pub fn get(&self, path: &Path, stamp: &FileStamp, algorithm: &str) -> rusqlite::Result<Option<String>> {
+        let Some(modified) = modified_nanos(stamp) else {
+            return Ok(None);
+        };
+
+        self.connection
+            .prepare_cached(
+                "SELECT hash FROM hashes
+                 WHERE path = ?1 AND algorithm = ?2 AND size = ?3 AND modified = ?4 AND inode = ?5",
+            )?
+            .query_row(params![cache_key(path), algorithm, stamp.size as i64, modified, stamp.inode as i64], |row| row.get(0))
+            .optional()
+    }
+
+    /// Store freshly computed hashes, replacing whatever was cached for those paths before
+    pub fn put_all(&mut self, algorithm: &str, entries: &[(&Path, &FileStamp, &str)]) -> rusqlite::Result<()> {
+        let transaction = self.connection.transaction()?;
+        {
+            let mut insert = transaction.prepare_cached(
+                "INSERT OR REPLACE INTO hashes (path, algorithm, size, modified, inode, hash)
+                 VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+            )?;
+            for (path, stamp, hash) in entries {
+                // Without a modification time there's no way to tell later whether the entry is stale
+                let Some(modified) = modified_nanos(stamp) else {
+                    continue;
+                };
+                insert.execute(params![cache_key(path), algorithm, stamp.size as i64, modified, stamp.inode as i64, hash])?;
+            }
+        }
+        transaction.commit()
+    }
+
+    /// Remove entries for files that are gone or have changed since they were hashed, and entries
+    /// keyed by a relative path, which older versions wrote. Returns how many entries were removed.
+    pub fn prune(&mut self) -> rusqlite::Result<usize> {
+        let entries: Vec<(String, i64, i64, i64)> = self
+            .connection
+            .prepare("SELECT DISTINCT path, size, modified, inode FROM hashes")?
+            .query_map([], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?, row.get(3)?)))?
+            .collect::<rusqlite::Result<_>>()?;
+
+        let transaction = self.connection.transaction()?;
+        let mut removed = 0;
+        for (path, size, modified, inode) in entries {
+            let current = unescape_path(&path)
+                .ok()
+                .filter(|path| path.is_absolute())
+                .and_then(|path| FileStamp::of(&path).ok());
+            let still_valid = current.is_some_and(|stamp| {
+                stamp.size as i64 == size && modified_nanos(&stamp) == Some(modified) && stamp.inode as i64 == inode
+            });
+            if !still_valid {
+                removed += transaction.execute(
+                    "DELETE FROM hashes WHERE path = ?1 AND size = ?2 AND modified = ?3 AND inode = ?4",
+                    params![path, size, modified, inode],
+                )?;
+            }
+        }
+        transaction.commit()?;
+        Ok(removed)
+    }
+
+    /// Forget every cached hash. Returns how many entries were removed.
+    pub fn clear(&mut self) -> rusqlite::Result<usize> {
+        self.connection.execute("DELETE FROM hashes", [])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn hashes_are_kept_until_the_file_changes() {
+        let dir = TempDir::new();
+        let mut cache = HashCache::open(&dir.path().join("cache").join("hashes.sqlite")).unwrap();
+        let kept = dir.write("a/kept.jpg", b"kept");
+        let edited = dir.write("a/edited.jpg", b"edited");
+        let deleted = dir.write("a/deleted.jpg", b"deleted");
+        let stamps: Vec<FileStamp> = [&kept, &edited, &deleted].iter().map(|path| FileStamp::of(path).unwrap()).collect();
+        let entries = [(kept.as_path(), &stamps[0], "1"), (edited.as_path(), &stamps[1], "2"), (deleted.as_path(), &stamps[2], "3")];
+        cache.put_all("sha256", &entries).unwrap();
+
+        // Any spelling of the path finds the entry, but only for the algorithm that made it
+        assert_eq!(cache.get(&dir.path().join("a/./kept.jpg"), &stamps[0], "sha256").unwrap().as_deref(), Some("1"));
+        assert_eq!(cache.get(&kept, &stamps[0], "phash-v2").unwrap(), None);
+
+        fs::write(&edited, b"edited again").unwrap();
+        assert_eq!(cache.get(&edited, &FileStamp::of(&edited).unwrap(), "sha256").unwrap(), None);
+        fs::remove_file(&deleted).unwrap();
+        assert_eq!(cache.prune().unwrap(), 2);
+        assert_eq!(cache.get(&kept, &stamps[0], "sha256").unwrap().as_deref(), Some("1"));
+        assert_eq!(cache.get(&edited, &stamps[1], "sha256").unwrap(), None);
+    }
+}
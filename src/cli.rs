@@ -0,0 +1,331 @@
+use std::path::PathBuf;
+
+use clap::{Args, Parser, Subcommand, ValueEnum};
+
+use dupchecker::actions::LinkKind;
+use dupchecker::cache::default_cache_path;
+use dupchecker::config::Config;
+use dupchecker::plan::Action;
+use dupchecker::scan::default_extensions;
+use dupchecker::{Detection, HashAlgorithm, HashMode, KeepPolicy, KeepRule, PerceptualAlgorithm, Scanner};
+
+#[derive(Parser)]
+#[command(name = "dupchecker", version, about = "Find and remove duplicate images")]
+#[command(args_conflicts_with_subcommands = true)]
+pub struct Cli {
+    /// Prompt for the folder, hash mode and delete confirmation on stdin
+    #[arg(long)]
+    pub interactive: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// List groups of duplicate images
+    Scan(ScanArgs),
+    /// Delete, quarantine, trash or link duplicate images, keeping one file of each group
+    Delete(DeleteArgs),
+    /// Write a report of duplicate images
+    Report(ReportArgs),
+    /// Remove the files an edited JSON, CSV or TSV report marks for removal
+    Apply(ApplyArgs),
+    /// Maintain the hash cache
+    Cache(CacheArgs),
+    /// Restore the files a delete run moved, trashed or linked, from its journal
+    Undo(UndoArgs),
+}
+
+#[derive(Args)]
+pub struct ScanArgs {
+    /// Folders to scan for images
+    #[arg(required = true, value_name = "DIRS")]
+    pub roots: Vec<PathBuf>,
+
+    /// Read-only folder to check DIRS against: only files in DIRS that duplicate a file here
+    /// are reported, and files here are never touched (repeatable)
+    #[arg(long = "reference", value_name = "DIR")]
+    pub references: Vec<PathBuf>,
+
+    /// How images are compared
+    #[arg(long, value_enum, default_value_t = ModeArg::Exact)]
+    pub mode: ModeArg,
+
+    /// Content hash used by the exact mode
+    #[arg(long = "hash", value_enum, default_value_t = HashArg::Md5)]
+    pub hash: HashArg,
+
+    /// Maximum Hamming distance between perceptual hashes to treat as duplicates (0-64)
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(0..=64))]
+    pub threshold: u32,
+
+    #[arg(long = "ext", value_name = "EXTS", value_delimiter = ',', help = extensions_help())]
+    pub extensions: Vec<String>,
+
+    /// Comma-separated extensions to leave out, even when included
+    #[arg(long = "exclude-ext", value_name = "EXTS", value_delimiter = ',')]
+    pub exclude: Vec<String>,
+
+    /// How to tell which files are images [default: extension]
+    #[arg(long, value_enum)]
+    pub detect: Option<DetectArg>,
+
+    /// Config file with default filters (defaults to $XDG_CONFIG_HOME/dupchecker/config.toml)
+    #[arg(long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Abort on the first unreadable file instead of skipping it
+    #[arg(long)]
+    pub strict: bool,
+
+    /// Number of files to hash in parallel (defaults to one per CPU core)
+    #[arg(short, long, value_name = "N", value_parser = clap::value_parser!(u64).range(1..))]
+    pub jobs: Option<u64>,
+
+    /// Hash cache file (defaults to $XDG_CACHE_HOME/dupchecker/hashes.sqlite)
+    #[arg(long, value_name = "FILE")]
+    pub cache: Option<PathBuf>,
+
+    /// Don't read or write the hash cache
+    #[arg(long, conflicts_with_all = ["cache", "rehash"])]
+    pub no_cache: bool,
+
+    /// Hash every file again, ignoring cached hashes
+    #[arg(long)]
+    pub rehash: bool,
+
+    /// Rule for picking the file to keep in each group: oldest, newest, shortest-path,
+    /// longest-path, largest, highest-resolution, prefer=DIR or match=REGEX.
+    /// Repeat to add tie-breakers; remaining ties keep the file whose path sorts first
+    #[arg(long = "keep", value_name = "RULE")]
+    pub keep: Vec<KeepRule>,
+}
+
+#[derive(Args)]
+pub struct DeleteArgs {
+    #[command(flatten)]
+    pub scan: ScanArgs,
+
+    #[command(flatten)]
+    pub execute: ExecuteArgs,
+}
+
+// How duplicates are removed, shared by every command that removes them
+#[derive(Args)]
+pub struct ExecuteArgs {
+    /// Print what would be deleted without touching any file
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Delete without asking for confirmation
+    #[arg(short = 'y', long = "yes")]
+    pub assume_yes: bool,
+
+    /// Move duplicates under this directory instead of deleting them.
+    /// With several folders, each folder's duplicates go in a subdirectory named after it
+    #[arg(long, value_name = "DIR", group = "action")]
+    pub quarantine: Option<PathBuf>,
+
+    /// Move duplicates to the desktop trash instead of deleting them
+    #[arg(long, group = "action")]
+    pub trash: bool,
+
+    /// Replace duplicates with links to the kept file instead of deleting them.
+    /// `auto` tries a reflink, then a hardlink, then a relative symlink
+    #[arg(long, value_enum, value_name = "KIND", num_args = 0..=1, default_missing_value = "auto", group = "action")]
+    pub link: Option<LinkArg>,
+
+    /// Record every file handled in this journal, appending if it exists
+    /// (defaults to a new file under $XDG_STATE_HOME/dupchecker/journals)
+    #[arg(long, value_name = "FILE")]
+    pub journal: Option<PathBuf>,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum LinkArg {
+    Auto,
+    Reflink,
+    Hard,
+    Symbolic,
+}
+
+impl ExecuteArgs {
+    pub fn action(&self) -> Action {
+        if let Some(quarantine) = &self.quarantine {
+            Action::Quarantine(quarantine.clone())
+        } else if self.trash {
+            Action::Trash
+        } else if let Some(link) = self.link {
+            Action::Link(match link {
+                LinkArg::Auto => LinkKind::Auto,
+                LinkArg::Reflink => LinkKind::Reflink,
+                LinkArg::Hard => LinkKind::Hard,
+                LinkArg::Symbolic => LinkKind::Symbolic,
+            })
+        } else {
+            Action::Delete
+        }
+    }
+}
+
+#[derive(Args)]
+pub struct ReportArgs {
+    #[command(flatten)]
+    pub scan: ScanArgs,
+
+    /// Report format
+    #[arg(long, value_enum, default_value_t = OutputFormat::Text)]
+    pub format: OutputFormat,
+
+    /// Write the report to this file instead of stdout
+    #[arg(short, long)]
+    pub output: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct ApplyArgs {
+    /// Report written by `report`, with keep/remove decisions edited by hand.
+    /// Files whose size, modification time or exact hash no longer match it are left alone
+    #[arg(value_name = "REPORT")]
+    pub report: PathBuf,
+
+    #[command(flatten)]
+    pub execute: ExecuteArgs,
+}
+
+#[derive(Args)]
+pub struct CacheArgs {
+    #[command(subcommand)]
+    pub action: CacheAction,
+
+    /// Hash cache file (defaults to $XDG_CACHE_HOME/dupchecker/hashes.sqlite)
+    #[arg(long, value_name = "FILE", global = true)]
+    pub cache: Option<PathBuf>,
+}
+
+#[derive(Args)]
+pub struct UndoArgs {
+    /// Journal written by an earlier delete run
+    #[arg(value_name = "JOURNAL")]
+    pub journal: PathBuf,
+}
+
+#[derive(Subcommand)]
+pub enum CacheAction {
+    /// Remove entries for files that were deleted or changed since they were hashed
+    Prune,
+    /// Remove every entry, forcing a full rehash on the next scan
+    Clear,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum ModeArg {
+    /// Identical file contents
+    Exact,
+    /// Average hash of an 8x8 thumbnail
+    Ahash,
+    /// Difference hash of a 9x8 thumbnail
+    Dhash,
+    /// DCT hash of a 32x32 thumbnail
+    Phash,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum DetectArg {
+    /// Trust file name extensions
+    Extension,
+    /// Read each file's first bytes, finding images with a missing or wrong extension
+    Content,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum HashArg {
+    /// MD5, compatible with earlier reports
+    Md5,
+    /// xxHash3, fast but not collision resistant
+    Xxh3,
+    /// BLAKE3, fast and collision resistant
+    Blake3,
+    /// SHA-256
+    Sha256,
+}
+
+#[derive(Clone, Copy, ValueEnum)]
+pub enum OutputFormat {
+    /// Plain text listing of the groups
+    Text,
+    /// Versioned JSON document with groups, file details, scan statistics and errors
+    Json,
+    /// Comma-separated values, one row per file
+    Csv,
+    /// Tab-separated values, one row per file
+    Tsv,
+    /// Single HTML page showing each group side by side with embedded thumbnails
+    Html,
+}
+
+impl HashArg {
+    fn algorithm(&self) -> HashAlgorithm {
+        match self {
+            HashArg::Md5 => HashAlgorithm::Md5,
+            HashArg::Xxh3 => HashAlgorithm::Xxh3,
+            HashArg::Blake3 => HashAlgorithm::Blake3,
+            HashArg::Sha256 => HashAlgorithm::Sha256,
+        }
+    }
+}
+
+// The --ext help, listing the defaults the way clap lists a default value
+fn extensions_help() -> String {
+    format!("Comma-separated image extensions to include [default: {}]", default_extensions().join(","))
+}
+
+impl ScanArgs {
+    // Set up a scanner the way the command-line flags ask, falling back on the config file
+    // for the filters they leave out
+    pub fn scanner(&self) -> Result<Scanner, Box<dyn std::error::Error>> {
+        let config = match &self.config {
+            Some(path) => Config::load(path)?,
+            None => Config::load_default()?,
+        };
+        // An empty list means the flag wasn't given
+        let given = |list: &Vec<String>| Some(list.clone()).filter(|list| !list.is_empty());
+        let extensions = given(&self.extensions)
+            .or(config.filter.include)
+            .unwrap_or_else(default_extensions);
+        let exclude = given(&self.exclude).or(config.filter.exclude).unwrap_or_default();
+        let detection = match self.detect {
+            Some(DetectArg::Extension) => Detection::Extension,
+            Some(DetectArg::Content) => Detection::Content,
+            None => config.filter.detect.unwrap_or_default(),
+        };
+
+        let algorithm = match self.mode {
+            ModeArg::Exact => None,
+            ModeArg::Ahash => Some(PerceptualAlgorithm::Average),
+            ModeArg::Dhash => Some(PerceptualAlgorithm::Difference),
+            ModeArg::Phash => Some(PerceptualAlgorithm::Dct),
+        };
+        let mode = match algorithm {
+            None => HashMode::Exact { algorithm: self.hash.algorithm() },
+            Some(algorithm) => HashMode::Perceptual { algorithm, threshold: self.threshold },
+        };
+
+        let scanner = Scanner::new()
+            .roots(&self.roots)
+            .references(&self.references)
+            .extensions(extensions)
+            .exclude(exclude)
+            .detection(detection)
+            .mode(mode)
+            .strict(self.strict)
+            .jobs(self.jobs.unwrap_or(0) as usize)
+            .rehash(self.rehash)
+            .keep(KeepPolicy { rules: self.keep.clone() });
+        Ok(match self.cache.clone().or_else(default_cache_path) {
+            Some(cache) if !self.no_cache => scanner.cache(cache),
+            _ => scanner,
+        })
+    }
+}
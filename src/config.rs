@@ -0,0 +1,66 @@
+//! The config file that sets a scan's default filters.
+
+use std::fs;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+
+use serde::Deserialize;
+
+use crate::escape::escape_path;
+use crate::filetype::Detection;
+
+/// Settings read from a TOML file, each overridden by the matching command-line flag:
+///
+/// ```toml
+/// [filter]
+/// include = ["jpg", "jpeg", "png"]
+/// exclude = ["gif"]
+/// detect = "content"
+/// ```
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Config {
+    /// The `[filter]` table
+    #[serde(default)]
+    pub filter: FilterConfig,
+}
+
+/// Which files a scan looks at
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FilterConfig {
+    /// Extensions to scan, or with content detection, the types to scan by their usual extensions
+    pub include: Option<Vec<String>>,
+    /// Extensions to leave out even when included
+    pub exclude: Option<Vec<String>>,
+    /// Whether files are told apart by extension or by content
+    pub detect: Option<Detection>,
+}
+
+/// Where the config lives unless told otherwise: $XDG_CONFIG_HOME/dupchecker/config.toml
+pub fn default_config_path() -> Option<PathBuf> {
+    let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".config"),
+    };
+    Some(config_dir.join("dupchecker").join("config.toml"))
+}
+
+impl Config {
+    /// Read a config file, failing on keys it doesn't know
+    pub fn load(path: &Path) -> Result<Config, Box<dyn std::error::Error>> {
+        let text = fs::read_to_string(path).map_err(|e| format!("cannot read {}: {}", escape_path(path), e))?;
+        toml::from_str(&text).map_err(|e| format!("{}: {}", escape_path(path), e).into())
+    }
+
+    /// The config at the default location, or the defaults when there is no such file
+    pub fn load_default() -> Result<Config, Box<dyn std::error::Error>> {
+        let Some(path) = default_config_path() else {
+            return Ok(Config::default());
+        };
+        match fs::metadata(&path) {
+            Err(e) if e.kind() == ErrorKind::NotFound => Ok(Config::default()),
+            _ => Config::load(&path),
+        }
+    }
+}
@@ -0,0 +1,49 @@
+use std::fs;
+use std::path::Path;
+
+use opencv::core::{Mat, Size, Vector};
+use opencv::imgcodecs::{imdecode, imencode, IMREAD_COLOR, IMREAD_UNCHANGED, IMWRITE_JPEG_QUALITY};
+use opencv::imgproc::{resize, INTER_AREA};
+use opencv::prelude::*;
+
+use crate::escape::escape_path;
+use crate::filetype::ImageType;
+use crate::raw::embedded_preview;
+
+// Decode an image with opencv, treating an empty result as the error it is. RAW files are
+// decoded through the JPEG preview embedded in them, and everything else is left to opencv,
+// whose build decides which of WebP, TIFF, AVIF, JPEG XL and HEIF it can read.
+// The file is read here rather than by imread, which only takes UTF-8 file names.
+pub fn read_image(image_path: &Path, flags: i32) -> Result<Mat, Box<dyn std::error::Error>> {
+    let contents = fs::read(image_path)?;
+    let encoded = match ImageType::identify(&contents, image_path) {
+        Some(image_type) if image_type.is_raw() => embedded_preview(&contents, image_type)
+            .ok_or_else(|| format!("No embedded preview found in {} file {}", image_type, escape_path(image_path)))?,
+        _ => &contents,
+    };
+    let image = imdecode(&Vector::<u8>::from_slice(encoded), flags)?;
+    if image.empty() {
+        return Err(format!("Could not decode image {}", escape_path(image_path)).into());
+    }
+    Ok(image)
+}
+
+// Width and height of an image in pixels
+pub fn image_dimensions(image_path: &Path) -> Result<(u32, u32), Box<dyn std::error::Error>> {
+    let image = read_image(image_path, IMREAD_UNCHANGED)?;
+    Ok((image.cols() as u32, image.rows() as u32))
+}
+
+// A JPEG no wider or taller than `max_side` pixels, for previews. Small images aren't enlarged.
+pub fn thumbnail_jpeg(image_path: &Path, max_side: i32) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let image = read_image(image_path, IMREAD_COLOR)?;
+    let (width, height) = (image.cols(), image.rows());
+    let scale = (max_side as f64 / width.max(height).max(1) as f64).min(1.0);
+    let size = Size::new(((width as f64 * scale) as i32).max(1), ((height as f64 * scale) as i32).max(1));
+
+    let mut thumbnail = Mat::default();
+    resize(&image, &mut thumbnail, size, 0.0, 0.0, INTER_AREA)?;
+    let mut jpeg = Vector::<u8>::new();
+    imencode(".jpg", &thumbnail, &mut jpeg, &Vector::from_slice(&[IMWRITE_JPEG_QUALITY, 80]))?;
+    Ok(jpeg.to_vec())
+}
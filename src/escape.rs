@@ -0,0 +1,118 @@
+//! Writing paths as single-line text that turns back into exactly the same path.
+
+use std::path::{Path, PathBuf};
+
+/// Write a path as text that can be turned back into exactly the same path.
+/// Valid UTF-8 comes out as it is, except that a backslash is doubled, control characters
+/// become \t, \n, \r or \xHH, and every byte that isn't valid UTF-8 becomes \xHH.
+/// The result never spans more than one line, so it is safe in line- and tab-based formats.
+/// On Unix the bytes are the path's own; elsewhere they are the system's encoding of it,
+/// which is UTF-8 for every path that is valid Unicode.
+pub fn escape_path(path: &Path) -> String {
+    let mut escaped = String::new();
+    for chunk in path.as_os_str().as_encoded_bytes().utf8_chunks() {
+        for c in chunk.valid().chars() {
+            match c {
+                '\\' => escaped.push_str("\\\\"),
+                '\t' => escaped.push_str("\\t"),
+                '\n' => escaped.push_str("\\n"),
+                '\r' => escaped.push_str("\\r"),
+                c if c.is_ascii_control() => escaped.push_str(&format!("\\x{:02x}", c as u8)),
+                c => escaped.push(c),
+            }
+        }
+        for byte in chunk.invalid() {
+            escaped.push_str(&format!("\\x{:02x}", byte));
+        }
+    }
+    escaped
+}
+
+/// Turn text written by escape_path back into the path it came from
+pub fn unescape_path(text: &str) -> Result<PathBuf, String> {
+    let mut bytes = Vec::with_capacity(text.len());
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c != '\\' {
+            let mut buffer = [0u8; 4];
+            bytes.extend_from_slice(c.encode_utf8(&mut buffer).as_bytes());
+            continue;
+        }
+        match chars.next() {
+            Some('\\') => bytes.push(b'\\'),
+            Some('t') => bytes.push(b'\t'),
+            Some('n') => bytes.push(b'\n'),
+            Some('r') => bytes.push(b'\r'),
+            Some('x') => {
+                let hex: String = chars.by_ref().take(2).collect();
+                let byte = u8::from_str_radix(&hex, 16)
+                    .ok()
+                    .filter(|_| hex.len() == 2 && hex.chars().all(|c| c.is_ascii_hexdigit()))
+                    .ok_or_else(|| format!("bad escape \\x{} in {}", hex, text))?;
+                bytes.push(byte);
+            }
+            other => {
+                return Err(format!("bad escape \\{} in {}", other.map(String::from).unwrap_or_default(), text));
+            }
+        }
+    }
+    path_from_bytes(bytes).ok_or_else(|| format!("{} is not a valid path on this system", text))
+}
+
+#[cfg(unix)]
+fn path_from_bytes(bytes: Vec<u8>) -> Option<PathBuf> {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+    Some(PathBuf::from(OsString::from_vec(bytes)))
+}
+
+// Only paths that are valid Unicode can be made from bytes here
+#[cfg(not(unix))]
+fn path_from_bytes(bytes: Vec<u8>) -> Option<PathBuf> {
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+// Paths that aren't UTF-8 can only be made from raw bytes on Unix
+#[cfg(all(test, unix))]
+mod tests {
+    use std::ffi::OsString;
+    use std::os::unix::ffi::OsStringExt;
+
+    use super::*;
+
+    fn round_trip(bytes: &[u8]) {
+        let path = PathBuf::from(OsString::from_vec(bytes.to_vec()));
+        let escaped = escape_path(&path);
+        assert!(!escaped.contains(['\n', '\r', '\t']), "{:?} escaped to {:?}", bytes, escaped);
+        assert_eq!(unescape_path(&escaped).unwrap(), path, "{:?} escaped to {:?}", bytes, escaped);
+    }
+
+    #[test]
+    fn plain_paths_are_left_alone() {
+        assert_eq!(escape_path(Path::new("photos/2024/café.jpg")), "photos/2024/café.jpg");
+    }
+
+    #[test]
+    fn special_characters_are_escaped() {
+        assert_eq!(escape_path(Path::new("a\\b\tc\nd\re\x07")), "a\\\\b\\tc\\nd\\re\\x07");
+        let invalid = PathBuf::from(OsString::from_vec(b"caf\xe9.jpg".to_vec()));
+        assert_eq!(escape_path(&invalid), "caf\\xe9.jpg");
+    }
+
+    #[test]
+    fn escaping_is_reversible() {
+        round_trip(b"photos/holiday.jpg");
+        round_trip(b"tab\tnewline\ncarriage\rreturn");
+        round_trip(b"back\\slash and \\x41 lookalike");
+        round_trip(b"latin1 caf\xe9, cut utf-8 \xc3 and \xff\xfe");
+        round_trip("emoji 📷 and ünïcode".as_bytes());
+        round_trip(&(1..=255).collect::<Vec<u8>>());
+    }
+
+    #[test]
+    fn bad_escapes_are_rejected() {
+        for text in ["trailing\\", "unknown\\q", "short\\x4", "not hex\\xzz"] {
+            assert!(unescape_path(text).is_err(), "{} was accepted", text);
+        }
+    }
+}
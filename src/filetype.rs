@@ -0,0 +1,335 @@
+//! The image types a scan recognises, by extension or by their first bytes.
+
+use std::fmt;
+use std::fs::File;
+use std::io::{self, ErrorKind, Read};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+/// The image formats a scan can recognise by their contents
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ImageType {
+    /// Portable Network Graphics
+    Png,
+    /// JPEG/JFIF, as most cameras and phones save
+    Jpeg,
+    /// GIF, still or animated
+    Gif,
+    /// Windows bitmap
+    Bmp,
+    /// Google's WebP
+    Webp,
+    /// TIFF, other than the camera RAW formats built on it
+    Tiff,
+    /// HEIC and other HEIF images
+    Heif,
+    /// AV1 images, in the same container as HEIF
+    Avif,
+    /// JPEG XL, bare or in its container
+    JpegXl,
+    /// Canon RAW, up to the EOS 5D Mark IV
+    Cr2,
+    /// Canon RAW, from the EOS R onwards
+    Cr3,
+    /// Nikon RAW
+    Nef,
+    /// Sony RAW
+    Arw,
+    /// Adobe's Digital Negative
+    Dng,
+    /// Olympus RAW
+    Orf,
+    /// Fujifilm RAW
+    Raf,
+}
+
+/// How a scan decides which files are images
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Detection {
+    /// Trust file name extensions
+    #[default]
+    Extension,
+    /// Read the first bytes of every file, so images without an extension or with the
+    /// wrong one are still found
+    Content,
+}
+
+// How much of a file is read to recognise it, enough for the brands of an ISO media file
+const SIGNATURE_BYTES: usize = 64;
+
+// ISO base media file brands, as found in the `ftyp` box HEIF, AVIF and CR3 files start with
+const AVIF_BRANDS: [&[u8; 4]; 2] = [b"avif", b"avis"];
+const HEIF_BRANDS: [&[u8; 4]; 10] =
+    [b"heic", b"heix", b"hevc", b"hevx", b"heim", b"heis", b"hevm", b"hevs", b"mif1", b"msf1"];
+const CR3_BRAND: &[u8; 4] = b"crx ";
+
+// The brands of an ISO base media file, major brand first, as far as the header goes
+fn brands(header: &[u8]) -> Vec<&[u8]> {
+    if header.get(4..8) != Some(b"ftyp") {
+        return Vec::new();
+    }
+    let box_size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+    let end = box_size.min(header.len());
+    let mut brands = Vec::new();
+    if let Some(major) = header.get(8..12) {
+        brands.push(major);
+    }
+    // Skip the minor version
+    let mut offset = 16;
+    while offset + 4 <= end {
+        brands.push(&header[offset..offset + 4]);
+        offset += 4;
+    }
+    brands
+}
+
+impl ImageType {
+    /// Every type, in the order the default extensions are listed in
+    pub const ALL: [ImageType; 16] = [
+        ImageType::Png,
+        ImageType::Jpeg,
+        ImageType::Gif,
+        ImageType::Bmp,
+        ImageType::Webp,
+        ImageType::Tiff,
+        ImageType::Heif,
+        ImageType::Avif,
+        ImageType::JpegXl,
+        ImageType::Cr2,
+        ImageType::Cr3,
+        ImageType::Nef,
+        ImageType::Arw,
+        ImageType::Dng,
+        ImageType::Orf,
+        ImageType::Raf,
+    ];
+
+    /// Extensions files of this type usually have, the usual one first
+    pub fn extensions(&self) -> &'static [&'static str] {
+        match self {
+            ImageType::Png => &["png"],
+            ImageType::Jpeg => &["jpg", "jpeg", "jpe"],
+            ImageType::Gif => &["gif"],
+            ImageType::Bmp => &["bmp", "dib"],
+            ImageType::Webp => &["webp"],
+            ImageType::Tiff => &["tif", "tiff"],
+            ImageType::Heif => &["heic", "heif", "hif"],
+            ImageType::Avif => &["avif"],
+            ImageType::JpegXl => &["jxl"],
+            ImageType::Cr2 => &["cr2"],
+            ImageType::Cr3 => &["cr3"],
+            ImageType::Nef => &["nef"],
+            ImageType::Arw => &["arw"],
+            ImageType::Dng => &["dng"],
+            ImageType::Orf => &["orf"],
+            ImageType::Raf => &["raf"],
+        }
+    }
+
+    /// Whether this is a camera RAW format, which is decoded through the preview embedded in it
+    pub fn is_raw(&self) -> bool {
+        matches!(
+            self,
+            ImageType::Cr2
+                | ImageType::Cr3
+                | ImageType::Nef
+                | ImageType::Arw
+                | ImageType::Dng
+                | ImageType::Orf
+                | ImageType::Raf
+        )
+    }
+
+    // RAW formats that are TIFF files underneath, and often can't be told from one by their first bytes
+    fn is_tiff_based(&self) -> bool {
+        matches!(self, ImageType::Cr2 | ImageType::Nef | ImageType::Arw | ImageType::Dng | ImageType::Orf)
+    }
+
+    /// The type a lowercase extension stands for
+    pub fn from_extension(extension: &str) -> Option<ImageType> {
+        ImageType::ALL.into_iter().find(|image_type| image_type.extensions().contains(&extension))
+    }
+
+    /// Recognise a type from the start of a file. Most TIFF-based RAW formats look like
+    /// any other TIFF file here, and a bare JPEG XL codestream starts with too little to go
+    /// on, see [`ImageType::identify`].
+    pub fn from_signature(header: &[u8]) -> Option<ImageType> {
+        let image_type = match header {
+            [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n', ..] => ImageType::Png,
+            [0xff, 0xd8, 0xff, ..] => ImageType::Jpeg,
+            [b'G', b'I', b'F', b'8', b'7' | b'9', b'a', ..] => ImageType::Gif,
+            // Two letters alone are too common, so the size of the header that follows must be
+            // one a BMP can have
+            [b'B', b'M', _, _, _, _, _, _, _, _, _, _, _, _, 12 | 16 | 40 | 52 | 56 | 64 | 108 | 124, 0, 0, 0, ..] => ImageType::Bmp,
+            [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => ImageType::Webp,
+            [b'I', b'I', b'*', 0, _, _, _, _, b'C', b'R', ..] => ImageType::Cr2,
+            [b'I', b'I', b'R', b'O' | b'S', ..] | [b'M', b'M', b'O', b'R', ..] => ImageType::Orf,
+            [b'I', b'I', b'*', 0, ..] | [b'M', b'M', 0, b'*', ..] => ImageType::Tiff,
+            [0, 0, 0, 0x0c, b'J', b'X', b'L', b' ', 0x0d, 0x0a, 0x87, 0x0a, ..] => ImageType::JpegXl,
+            _ if header.starts_with(b"FUJIFILMCCD-RAW") => ImageType::Raf,
+            _ => {
+                let brands = brands(header);
+                if brands.contains(&CR3_BRAND.as_slice()) {
+                    ImageType::Cr3
+                } else if AVIF_BRANDS.iter().any(|brand| brands.contains(&brand.as_slice())) {
+                    ImageType::Avif
+                } else if HEIF_BRANDS.iter().any(|brand| brands.contains(&brand.as_slice())) {
+                    ImageType::Heif
+                } else {
+                    return None;
+                }
+            }
+        };
+        Some(image_type)
+    }
+
+    /// Recognise a file's type from its first bytes, letting its extension settle which
+    /// TIFF-based RAW format a TIFF file is. A bare JPEG XL codestream, whose signature is
+    /// only two bytes long, also needs its extension to be taken for one.
+    pub fn identify(header: &[u8], path: &Path) -> Option<ImageType> {
+        let named = path.extension().and_then(|extension| extension.to_str()).map(str::to_lowercase);
+        let named = named.as_deref().and_then(ImageType::from_extension);
+        let Some(detected) = ImageType::from_signature(header) else {
+            return (header.starts_with(&[0xff, 0x0a]) && named == Some(ImageType::JpegXl)).then_some(ImageType::JpegXl);
+        };
+        match named {
+            Some(named) if detected == ImageType::Tiff && named.is_tiff_based() => Some(named),
+            _ => Some(detected),
+        }
+    }
+
+    /// Read just enough of a file to tell which type it is, None if it isn't one we know
+    pub fn detect(path: &Path) -> io::Result<Option<ImageType>> {
+        let mut file = File::open(path)?;
+        let mut header = [0u8; SIGNATURE_BYTES];
+        let mut read = 0;
+        // Short reads are allowed, and files shorter than a signature are simply not images
+        while read < header.len() {
+            match file.read(&mut header[read..]) {
+                Ok(0) => break,
+                Ok(n) => read += n,
+                Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(ImageType::identify(&header[..read], path))
+    }
+
+    /// Whether any of this type's extensions is in the list
+    pub fn listed_in(&self, extensions: &[String]) -> bool {
+        self.extensions().iter().any(|extension| extensions.iter().any(|listed| listed == extension))
+    }
+}
+
+impl fmt::Display for ImageType {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ImageType::Png => "PNG",
+            ImageType::Jpeg => "JPEG",
+            ImageType::Gif => "GIF",
+            ImageType::Bmp => "BMP",
+            ImageType::Webp => "WebP",
+            ImageType::Tiff => "TIFF",
+            ImageType::Heif => "HEIF",
+            ImageType::Avif => "AVIF",
+            ImageType::JpegXl => "JPEG XL",
+            ImageType::Cr2 => "Canon CR2",
+            ImageType::Cr3 => "Canon CR3",
+            ImageType::Nef => "Nikon NEF",
+            ImageType::Arw => "Sony ARW",
+            ImageType::Dng => "DNG",
+            ImageType::Orf => "Olympus ORF",
+            ImageType::Raf => "Fujifilm RAF",
+        };
+        f.write_str(name)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // An ISO base media file header with a major brand and a list of compatible ones
+    fn ftyp(major: &[u8; 4], compatible: &[&[u8; 4]]) -> Vec<u8> {
+        let size = 16 + 4 * compatible.len() as u32;
+        let mut header = size.to_be_bytes().to_vec();
+        header.extend(b"ftyp");
+        header.extend(major);
+        header.extend([0, 0, 0, 0]);
+        for brand in compatible {
+            header.extend(*brand);
+        }
+        header
+    }
+
+    #[test]
+    fn signatures_are_recognised() {
+        let cases: [(&[u8], ImageType); 11] = [
+            (b"\x89PNG\r\n\x1a\n\0\0\0\rIHDR", ImageType::Png),
+            (b"\xff\xd8\xff\xe0\0\x10JFIF", ImageType::Jpeg),
+            (b"GIF89a\x01\0", ImageType::Gif),
+            (b"BM\x36\0\0\0\0\0\0\0\x36\0\0\0\x28\0\0\0", ImageType::Bmp),
+            (b"RIFF\x24\0\0\0WEBPVP8 ", ImageType::Webp),
+            (b"II*\0\x08\0\0\0", ImageType::Tiff),
+            (b"MM\0*\0\0\0\x08", ImageType::Tiff),
+            (b"II*\0\x10\0\0\0CR\x02\0", ImageType::Cr2),
+            (b"IIRO\x08\0\0\0", ImageType::Orf),
+            (b"\0\0\0\x0cJXL \r\n\x87\n", ImageType::JpegXl),
+            (b"FUJIFILMCCD-RAW 0201", ImageType::Raf),
+        ];
+        for (header, expected) in cases {
+            assert_eq!(ImageType::from_signature(header), Some(expected), "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn brands_tell_iso_media_files_apart() {
+        assert_eq!(ImageType::from_signature(&ftyp(b"heic", &[b"mif1", b"heic"])), Some(ImageType::Heif));
+        assert_eq!(ImageType::from_signature(&ftyp(b"avif", &[b"mif1", b"miaf"])), Some(ImageType::Avif));
+        // AVIF files may put a generic HEIF brand first
+        assert_eq!(ImageType::from_signature(&ftyp(b"mif1", &[b"miaf", b"avif"])), Some(ImageType::Avif));
+        assert_eq!(ImageType::from_signature(&ftyp(b"crx ", &[b"crx ", b"isom"])), Some(ImageType::Cr3));
+        assert_eq!(ImageType::from_signature(&ftyp(b"isom", &[b"iso2", b"mp41"])), None);
+    }
+
+    #[test]
+    fn other_files_are_not_images() {
+        for header in [
+            &b""[..],
+            b"\x89PN",
+            b"%PDF-1.7",
+            b"PK\x03\x04",
+            b"RIFF\x24\0\0\0WAVEfmt ",
+            b"GIF90a",
+            b"BMW owners' club minutes",
+            b"BM\x36\0\0\0\0\0\0\0\x36\0\0\0\x29\0\0\0",
+            b"\xff\x0a\xfa binary that isn't JPEG XL",
+        ] {
+            assert_eq!(ImageType::from_signature(header), None, "{:?}", header);
+            assert_eq!(ImageType::identify(header, Path::new("notes.txt")), None, "{:?}", header);
+        }
+    }
+
+    #[test]
+    fn extensions_settle_tiff_based_raw_formats() {
+        let tiff = b"II*\0\x08\0\0\0";
+        assert_eq!(ImageType::identify(tiff, Path::new("DSC_0001.NEF")), Some(ImageType::Nef));
+        assert_eq!(ImageType::identify(tiff, Path::new("a.dng")), Some(ImageType::Dng));
+        assert_eq!(ImageType::identify(tiff, Path::new("a.tif")), Some(ImageType::Tiff));
+        assert_eq!(ImageType::identify(tiff, Path::new("a.jpg")), Some(ImageType::Tiff));
+        assert_eq!(ImageType::identify(b"\xff\x0a\xfa", Path::new("a.jxl")), Some(ImageType::JpegXl));
+        // The extension never overrides a signature that says otherwise
+        assert_eq!(ImageType::identify(b"\x89PNG\r\n\x1a\n", Path::new("a.nef")), Some(ImageType::Png));
+    }
+
+    #[test]
+    fn every_extension_belongs_to_one_type() {
+        for image_type in ImageType::ALL {
+            for extension in image_type.extensions() {
+                assert_eq!(ImageType::from_extension(extension), Some(image_type), "{}", extension);
+            }
+        }
+    }
+}
@@ -0,0 +1,94 @@
+//! The content hash algorithms exact scans compare files with.
+
+use sha2::Digest;
+
+/// The content hash algorithms files can be compared with
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HashAlgorithm {
+    /// Kept as the default so hashes match earlier reports
+    Md5,
+    /// Fast non-cryptographic hash, fine for quick scans
+    Xxh3,
+    /// Fast and collision resistant
+    Blake3,
+    /// Slower, for reports that have to name a standard algorithm
+    Sha256,
+}
+
+impl HashAlgorithm {
+    /// The name hashes are tagged with in reports and the cache
+    pub fn name(&self) -> &'static str {
+        match self {
+            HashAlgorithm::Md5 => "md5",
+            HashAlgorithm::Xxh3 => "xxh3",
+            HashAlgorithm::Blake3 => "blake3",
+            HashAlgorithm::Sha256 => "sha256",
+        }
+    }
+
+    /// The algorithm a tagged hash was made with, looked up by the name `name` gives it
+    pub fn from_name(name: &str) -> Option<HashAlgorithm> {
+        [HashAlgorithm::Md5, HashAlgorithm::Xxh3, HashAlgorithm::Blake3, HashAlgorithm::Sha256]
+            .into_iter()
+            .find(|algorithm| algorithm.name() == name)
+    }
+
+    /// Start a new incremental hash
+    pub fn hasher(&self) -> Box<dyn ContentHasher> {
+        match self {
+            HashAlgorithm::Md5 => Box::new(md5::Context::new()),
+            HashAlgorithm::Xxh3 => Box::new(xxhash_rust::xxh3::Xxh3::new()),
+            HashAlgorithm::Blake3 => Box::new(blake3::Hasher::new()),
+            HashAlgorithm::Sha256 => Box::new(sha2::Sha256::new()),
+        }
+    }
+}
+
+/// A hash that is fed a file a buffer at a time and yields a hex digest
+pub trait ContentHasher {
+    /// Feed the next bytes of the file
+    fn update(&mut self, data: &[u8]);
+    /// The digest of everything fed so far, as lowercase hexadecimal
+    fn finish(self: Box<Self>) -> String;
+}
+
+impl ContentHasher for md5::Context {
+    fn update(&mut self, data: &[u8]) {
+        self.consume(data);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        format!("{:x}", self.compute())
+    }
+}
+
+impl ContentHasher for xxhash_rust::xxh3::Xxh3 {
+    fn update(&mut self, data: &[u8]) {
+        xxhash_rust::xxh3::Xxh3::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        // The 128-bit digest keeps accidental collisions out of large libraries
+        format!("{:032x}", self.digest128())
+    }
+}
+
+impl ContentHasher for blake3::Hasher {
+    fn update(&mut self, data: &[u8]) {
+        blake3::Hasher::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        self.finalize().to_hex().to_string()
+    }
+}
+
+impl ContentHasher for sha2::Sha256 {
+    fn update(&mut self, data: &[u8]) {
+        Digest::update(self, data);
+    }
+
+    fn finish(self: Box<Self>) -> String {
+        format!("{:x}", self.finalize())
+    }
+}
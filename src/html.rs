@@ -0,0 +1,166 @@
+//! The HTML report, with thumbnails for going through groups by eye.
+
+use std::io::{self, Write};
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use rayon::prelude::*;
+
+use crate::decode::thumbnail_jpeg;
+use crate::escape::unescape_path;
+use crate::plan::format_bytes;
+use crate::report::{Report, ReportFile};
+
+// Longest side of the embedded thumbnails, in pixels
+const THUMBNAIL_SIDE: i32 = 240;
+
+const STYLE: &str = "
+body { font-family: system-ui, sans-serif; margin: 2em; color: #222; background: #fafafa; }
+h1 { margin-bottom: 0.2em; }
+.summary { color: #555; margin-bottom: 2em; }
+section { background: #fff; border: 1px solid #ddd; border-radius: 6px; padding: 1em; margin-bottom: 1.5em; }
+section h2 { font-size: 1em; margin: 0 0 0.8em; }
+section h2 code { font-weight: normal; color: #666; word-break: break-all; }
+.files { display: flex; flex-wrap: wrap; gap: 1em; }
+figure { margin: 0; width: 260px; border: 2px solid #ddd; border-radius: 6px; padding: 0.5em; }
+figure.keep { border-color: #2e7d32; }
+figure.remove { border-color: #c62828; }
+.preview { height: 240px; display: flex; align-items: center; justify-content: center; background: #eee; }
+.preview img { max-width: 240px; max-height: 240px; }
+.badge { display: inline-block; font-size: 0.75em; font-weight: bold; padding: 0.1em 0.5em; border-radius: 3px; color: #fff; }
+.keep .badge { background: #2e7d32; }
+.remove .badge { background: #c62828; }
+figcaption { font-size: 0.85em; margin-top: 0.5em; }
+.path { word-break: break-all; font-family: monospace; }
+.details { color: #555; }
+";
+
+// Make text safe to put inside HTML elements and attribute values
+fn escape_html(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '&' => escaped.push_str("&amp;"),
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+// An RFC 3339 timestamp from the report, shortened to the second for people to read
+fn readable_time(timestamp: &str) -> String {
+    match timestamp.get(..19) {
+        Some(seconds) => format!("{} UTC", seconds.replace('T', " ")),
+        None => timestamp.to_string(),
+    }
+}
+
+// One file of a group as a card: thumbnail, keep/remove badge, path and details
+fn write_file(out: &mut dyn Write, file: &ReportFile, thumbnail: &Option<String>) -> io::Result<()> {
+    let class = if file.keep { "keep" } else { "remove" };
+    writeln!(out, "<figure class=\"{}\">", class)?;
+    match thumbnail {
+        Some(data) => writeln!(out, "<div class=\"preview\"><img src=\"data:image/jpeg;base64,{}\" alt=\"\"></div>", data)?,
+        None => writeln!(out, "<div class=\"preview\">No preview</div>")?,
+    }
+
+    let mut details = Vec::new();
+    if let (Some(width), Some(height)) = (file.width, file.height) {
+        details.push(format!("{} &times; {}", width, height));
+    }
+    details.push(format_bytes(file.size));
+    if let Some(modified) = &file.modified {
+        details.push(escape_html(&readable_time(modified)));
+    }
+    writeln!(
+        out,
+        "<figcaption><span class=\"badge\">{}</span><div class=\"path\">{}</div><div class=\"details\">{}</div></figcaption>",
+        class,
+        escape_html(&file.path),
+        details.join(" &middot; ")
+    )?;
+    writeln!(out, "</figure>")
+}
+
+/// Write the report as a single HTML page, every group side by side with thumbnails embedded
+/// as base64 so the file can be mailed or opened offline on its own
+pub fn write_html_report(report: &Report, out: &mut dyn Write) -> io::Result<()> {
+    writeln!(out, "<!DOCTYPE html>")?;
+    writeln!(out, "<html lang=\"en\">\n<head>\n<meta charset=\"utf-8\">")?;
+    writeln!(out, "<title>Duplicate images</title>\n<style>{}</style>\n</head>\n<body>", STYLE)?;
+    writeln!(out, "<h1>Duplicate images</h1>")?;
+    writeln!(
+        out,
+        "<p class=\"summary\">{} group(s), {} duplicate file(s), {} reclaimable. Scanned {} file(s) in {} by {}, keeping one file per group by {}. Generated {}.</p>",
+        report.stats.groups,
+        report.stats.duplicate_files,
+        format_bytes(report.stats.reclaimable_bytes),
+        report.stats.files_scanned,
+        escape_html(&report.roots.join(", ")),
+        escape_html(&report.algorithm),
+        escape_html(&report.keep_policy),
+        escape_html(&readable_time(&report.generated))
+    )?;
+
+    for group in &report.groups {
+        // Decoding is the slow part, so thumbnail a group's files in parallel
+        let thumbnails: Vec<Option<String>> = group
+            .files
+            .par_iter()
+            .map(|file| {
+                let path = unescape_path(&file.path).ok()?;
+                thumbnail_jpeg(&path, THUMBNAIL_SIDE).ok().map(|jpeg| STANDARD.encode(jpeg))
+            })
+            .collect();
+
+        writeln!(out, "<section>")?;
+        writeln!(
+            out,
+            "<h2>Group {} &middot; reclaims {} <code>{}</code></h2>",
+            group.id,
+            format_bytes(group.reclaimable_bytes),
+            escape_html(&group.hash)
+        )?;
+        writeln!(out, "<div class=\"files\">")?;
+        for (file, thumbnail) in group.files.iter().zip(&thumbnails) {
+            write_file(out, file, thumbnail)?;
+        }
+        writeln!(out, "</div>\n</section>")?;
+    }
+    if report.groups.is_empty() {
+        writeln!(out, "<p>No duplicate images found.</p>")?;
+    }
+
+    if !report.errors.is_empty() {
+        writeln!(out, "<h2>Skipped {} unreadable file(s)</h2>\n<ul>", report.errors.len())?;
+        for error in &report.errors {
+            writeln!(
+                out,
+                "<li><span class=\"path\">{}</span> ({} error): {}</li>",
+                escape_html(&error.path),
+                escape_html(&error.kind),
+                escape_html(&error.message)
+            )?;
+        }
+        writeln!(out, "</ul>")?;
+    }
+
+    if !report.mismatches.is_empty() {
+        writeln!(out, "<h2>{} image(s) with a misleading extension</h2>\n<ul>", report.mismatches.len())?;
+        for mismatch in &report.mismatches {
+            writeln!(
+                out,
+                "<li><span class=\"path\">{}</span> holds a {} image, usually named .{}</li>",
+                escape_html(&mismatch.path),
+                escape_html(&mismatch.detected),
+                escape_html(&mismatch.extension)
+            )?;
+        }
+        writeln!(out, "</ul>")?;
+    }
+    writeln!(out, "</body>\n</html>")
+}
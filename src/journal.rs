@@ -0,0 +1,340 @@
+//! The journal of everything a run did to files, and undoing it.
+
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::actions::{self, LinkKind};
+use crate::escape::{escape_path, unescape_path};
+use crate::plan::{Action, Outcome, PlannedFile};
+use crate::scan::FileStamp;
+use crate::trash;
+use crate::verify::same_contents;
+
+// First line of every journal, so undo never acts on some other file by mistake
+const JOURNAL_HEADER: &str = "# dupchecker journal v1\taction\tpath\thash\tsize\tmodified\tdestination";
+
+/// What was done to a file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum JournalAction {
+    /// Removed for good, which can't be undone
+    Delete,
+    /// Moved into a quarantine folder
+    Move,
+    /// Moved to the desktop trash
+    Trash,
+    /// Replaced with a link of this kind to the kept file
+    Link(LinkKind),
+}
+
+impl JournalAction {
+    fn name(&self) -> &'static str {
+        match self {
+            JournalAction::Delete => "delete",
+            JournalAction::Move => "move",
+            JournalAction::Trash => "trash",
+            JournalAction::Link(LinkKind::Hard) => "hardlink",
+            JournalAction::Link(LinkKind::Symbolic) => "symlink",
+            JournalAction::Link(LinkKind::Reflink) => "reflink",
+            // link_file always reports the kind of link it actually made
+            JournalAction::Link(LinkKind::Auto) => "link",
+        }
+    }
+
+    fn from_name(name: &str) -> Option<JournalAction> {
+        match name {
+            "delete" => Some(JournalAction::Delete),
+            "move" => Some(JournalAction::Move),
+            "trash" => Some(JournalAction::Trash),
+            "hardlink" => Some(JournalAction::Link(LinkKind::Hard)),
+            "symlink" => Some(JournalAction::Link(LinkKind::Symbolic)),
+            "reflink" => Some(JournalAction::Link(LinkKind::Reflink)),
+            _ => None,
+        }
+    }
+}
+
+/// One line of the journal: a file as it was before it was acted on, and where it went.
+/// For links the destination is the kept file the link points at.
+#[derive(Clone, Debug)]
+pub struct JournalEntry {
+    /// What was done
+    pub action: JournalAction,
+    /// Where the file was
+    pub path: PathBuf,
+    /// The hash of the group it was removed from
+    pub hash: String,
+    /// Its size before it was acted on
+    pub size: u64,
+    /// Its modification time, which undo gives back to files restored from a link. None when
+    /// the filesystem doesn't record one.
+    pub modified: Option<SystemTime>,
+    /// Where it was moved, or the file a link points at. None for deletions and the trash.
+    pub destination: Option<PathBuf>,
+}
+
+/// Where a run's journal goes unless told otherwise: a new file per run under
+/// $XDG_STATE_HOME/dupchecker/journals, named after the time it started
+pub fn default_journal_path() -> Option<PathBuf> {
+    let state_dir = match std::env::var_os("XDG_STATE_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => PathBuf::from(std::env::var_os("HOME")?).join(".local").join("state"),
+    };
+    let name = format!("{}-{}.tsv", trash::local_time().replace(':', "-"), std::process::id());
+    Some(state_dir.join("dupchecker").join("journals").join(name))
+}
+
+impl JournalEntry {
+    fn to_line(&self) -> String {
+        let modified = self
+            .modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .map(|since_epoch| since_epoch.as_nanos().to_string())
+            .unwrap_or_default();
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\n",
+            self.action.name(),
+            escape_path(&self.path),
+            self.hash,
+            self.size,
+            modified,
+            self.destination.as_deref().map(escape_path).unwrap_or_default()
+        )
+    }
+
+    fn from_line(line: &str) -> Result<JournalEntry, String> {
+        let fields: Vec<&str> = line.split('\t').collect();
+        let [action, path, hash, size, modified, destination] = fields[..] else {
+            return Err(format!("expected 6 fields, found {}", fields.len()));
+        };
+
+        let action = JournalAction::from_name(action).ok_or_else(|| format!("unknown action '{}'", action))?;
+        let size = size.parse::<u64>().map_err(|e| format!("bad size: {}", e))?;
+        let modified = match modified {
+            "" => None,
+            nanos => {
+                let nanos = nanos.parse::<u64>().map_err(|e| format!("bad modification time: {}", e))?;
+                Some(UNIX_EPOCH + Duration::from_nanos(nanos))
+            }
+        };
+        let destination = match destination {
+            "" => None,
+            destination => Some(unescape_path(destination)?),
+        };
+        Ok(JournalEntry { action, path: unescape_path(path)?, hash: hash.to_string(), size, modified, destination })
+    }
+}
+
+/// An append-only record of everything a run did, one line per file, written as each
+/// operation completes so it is complete up to the moment a run stops for any reason
+pub struct Journal {
+    file: File,
+    /// Where the journal is written
+    pub path: PathBuf,
+}
+
+impl Journal {
+    /// Open a journal for appending, creating it and its folder if need be
+    pub fn create(path: &Path) -> io::Result<Journal> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = OpenOptions::new().append(true).create(true).open(path)?;
+        if file.metadata()?.len() == 0 {
+            file.write_all(format!("{}\n", JOURNAL_HEADER).as_bytes())?;
+        }
+        Ok(Journal { file, path: path.to_path_buf() })
+    }
+
+    /// Append what just happened to one file. Each line goes out in a single unbuffered write.
+    pub fn record(&mut self, action: &Action, hash: &str, file: &PlannedFile, outcome: &Outcome) -> io::Result<()> {
+        let (action, destination) = match outcome {
+            Outcome::Deleted => (JournalAction::Delete, None),
+            Outcome::MovedTo(destination) => {
+                let action = match action {
+                    Action::Trash => JournalAction::Trash,
+                    _ => JournalAction::Move,
+                };
+                (action, Some(destination))
+            }
+            Outcome::LinkedTo(keep, kind) => (JournalAction::Link(*kind), Some(keep)),
+        };
+        // Paths are made absolute so undo works from any directory
+        let absolute = |path: &Path| std::path::absolute(path).unwrap_or_else(|_| path.to_path_buf());
+        let entry = JournalEntry {
+            action,
+            path: absolute(&file.path),
+            hash: hash.to_string(),
+            size: file.stamp.size,
+            modified: file.stamp.modified,
+            destination: destination.map(|destination| absolute(destination)),
+        };
+        self.file.write_all(entry.to_line().as_bytes())
+    }
+}
+
+/// Read every entry of a journal, in the order the operations happened
+pub fn read_journal(path: &Path) -> Result<Vec<JournalEntry>, Box<dyn std::error::Error>> {
+    let contents = fs::read_to_string(path)?;
+    let mut lines = contents.lines();
+    if lines.next() != Some(JOURNAL_HEADER) {
+        return Err(format!("{} is not a dupchecker journal", escape_path(path)).into());
+    }
+
+    let mut entries = Vec::new();
+    for (number, line) in lines.enumerate() {
+        if line.is_empty() {
+            continue;
+        }
+        // The header is line 1
+        let entry = JournalEntry::from_line(line).map_err(|e| format!("{}:{}: {}", escape_path(path), number + 2, e))?;
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+// Make sure the file that was moved away is still the one the journal describes
+fn check_moved(entry: &JournalEntry, destination: &Path) -> Result<(), String> {
+    let stamp = FileStamp::of(destination).map_err(|e| format!("cannot stat {}: {}", escape_path(destination), e))?;
+    if stamp.size != entry.size || stamp.modified != entry.modified {
+        return Err(format!("{} changed since it was moved there", escape_path(destination)));
+    }
+    Ok(())
+}
+
+// Make sure the path still holds the link that was made, and not something saved there since
+fn check_linked(entry: &JournalEntry, keep: &Path) -> Result<(), String> {
+    let path = entry.path.as_path();
+    let still_linked = match entry.action {
+        JournalAction::Link(LinkKind::Hard) => match (fs::symlink_metadata(path), fs::metadata(keep)) {
+            (Ok(link), Ok(keep_metadata)) => {
+                match (FileStamp::from_metadata(&link).file_id(), FileStamp::from_metadata(&keep_metadata).file_id()) {
+                    (Some(link), Some(keep)) => link == keep,
+                    // Without inodes to go by, a file identical to the kept one is as good as the link
+                    _ => same_contents(path, keep).unwrap_or(false),
+                }
+            }
+            _ => false,
+        },
+        JournalAction::Link(LinkKind::Symbolic) => {
+            let is_symlink = fs::symlink_metadata(path).map(|m| m.file_type().is_symlink()).unwrap_or(false);
+            is_symlink && fs::canonicalize(path).ok() == fs::canonicalize(keep).ok()
+        }
+        _ => true,
+    };
+    if !still_linked {
+        return Err(format!("{} is no longer a link to {}", escape_path(path), escape_path(keep)));
+    }
+    Ok(())
+}
+
+// Reverse one journal entry, returning a note on what was done
+fn undo_entry(entry: &JournalEntry) -> Result<String, String> {
+    let original = entry.path.as_path();
+    let destination = entry.destination.as_deref();
+    match (entry.action, destination) {
+        (JournalAction::Delete, _) => Err("it was deleted".to_string()),
+        (JournalAction::Move, Some(destination)) => {
+            check_moved(entry, destination)?;
+            actions::move_file(destination, original).map_err(|e| e.to_string())?;
+            Ok(format!("{} -> {}", escape_path(destination), escape_path(original)))
+        }
+        (JournalAction::Trash, Some(destination)) => {
+            check_moved(entry, destination)?;
+            trash::restore_file(destination, original).map_err(|e| e.to_string())?;
+            Ok(format!("{} -> {}", escape_path(destination), escape_path(original)))
+        }
+        // A reflink was never anything but a file of its own
+        (JournalAction::Link(LinkKind::Reflink), Some(_)) => Ok(format!("{} (reflink, nothing to undo)", escape_path(original))),
+        (JournalAction::Link(kind), Some(keep)) => {
+            check_linked(entry, keep)?;
+            actions::unlink_file(original, keep, entry.modified).map_err(|e| match e.kind() {
+                ErrorKind::NotFound => format!("{} is gone: {}", escape_path(keep), e),
+                _ => e.to_string(),
+            })?;
+            Ok(format!("{} (was a {} to {})", escape_path(original), kind, escape_path(keep)))
+        }
+        (_, None) => Err("the journal does not say where it went".to_string()),
+    }
+}
+
+/// What undoing a journal managed to do
+#[derive(Default)]
+pub struct UndoSummary {
+    /// Files put back
+    pub restored: usize,
+    /// Files that couldn't be
+    pub unrestorable: usize,
+    /// Every file in the journal, latest first, with a note on how it was restored or the
+    /// reason it couldn't be
+    pub files: Vec<(PathBuf, Result<String, String>)>,
+}
+
+/// Put back everything a journal records, latest operation first. Prints nothing: what
+/// became of each file is in the summary.
+pub fn undo(entries: &[JournalEntry]) -> UndoSummary {
+    let mut summary = UndoSummary::default();
+    for entry in entries.iter().rev() {
+        let result = undo_entry(entry);
+        match result {
+            Ok(_) => summary.restored += 1,
+            Err(_) => summary.unrestorable += 1,
+        }
+        summary.files.push((entry.path.clone(), result));
+    }
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn entries_survive_the_round_trip() {
+        let entry = JournalEntry {
+            action: JournalAction::Link(LinkKind::Symbolic),
+            path: PathBuf::from("/photos/tab\there.jpg"),
+            hash: "sha256:abc".to_string(),
+            size: 42,
+            modified: Some(UNIX_EPOCH + Duration::from_nanos(1_700_000_000_123_456_789)),
+            destination: Some(PathBuf::from("/photos/line\nbreak.jpg")),
+        };
+        let line = entry.to_line();
+        let parsed = JournalEntry::from_line(line.strip_suffix('\n').unwrap()).unwrap();
+        assert_eq!(parsed.to_line(), line);
+        assert_eq!((parsed.action, &parsed.path, parsed.modified), (entry.action, &entry.path, entry.modified));
+
+        let deleted = JournalEntry { action: JournalAction::Delete, modified: None, destination: None, ..entry };
+        let parsed = JournalEntry::from_line(deleted.to_line().strip_suffix('\n').unwrap()).unwrap();
+        assert_eq!((parsed.modified, parsed.destination), (None, None));
+        assert!(JournalEntry::from_line("unlink\t/a.jpg\th\t1\t\t").is_err());
+        assert!(JournalEntry::from_line("move\t/a.jpg\th\t1\t").is_err());
+    }
+
+    #[test]
+    fn undo_puts_moved_files_back() {
+        let dir = TempDir::new();
+        let original = dir.write("a.jpg", b"picture");
+        let destination = dir.path().join("q/a.jpg");
+        let file = PlannedFile { stamp: FileStamp::of(&original).unwrap(), path: original.clone() };
+        actions::move_file(&original, &destination).unwrap();
+
+        let path = dir.path().join("journal.tsv");
+        let mut journal = Journal::create(&path).unwrap();
+        let action = Action::Quarantine(dir.path().join("q"));
+        journal.record(&action, "sha256:abc", &file, &Outcome::MovedTo(destination.clone())).unwrap();
+        journal.record(&Action::Delete, "sha256:abc", &file, &Outcome::Deleted).unwrap();
+
+        let summary = undo(&read_journal(&path).unwrap());
+        assert_eq!((summary.restored, summary.unrestorable), (1, 1));
+        assert_eq!(fs::read(&original).unwrap(), b"picture");
+        assert!(!destination.exists());
+
+        // The quarantined file is gone now, so a second undo can't restore anything
+        let summary = undo(&read_journal(&path).unwrap());
+        assert_eq!((summary.restored, summary.unrestorable), (0, 2));
+        assert!(read_journal(&original).is_err());
+    }
+}
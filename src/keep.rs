@@ -0,0 +1,232 @@
+//! Deciding which file of each duplicate group is kept.
+
+use std::cmp::Ordering;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+use regex::bytes::Regex;
+
+use crate::decode::image_dimensions;
+use crate::scan::{DuplicateGroup, FileEntry, FileStamp};
+
+/// One way of deciding which copy in a group survives. Parses from the names the `--keep`
+/// option takes, such as `oldest`, `prefer=DIR` or `match=REGEX`.
+#[derive(Clone, Debug)]
+pub enum KeepRule {
+    /// Earliest modification time
+    Oldest,
+    /// Latest modification time
+    Newest,
+    /// Fewest bytes in the path
+    ShortestPath,
+    /// Most bytes in the path
+    LongestPath,
+    /// Biggest file, which matters for perceptual groups
+    Largest,
+    /// Most pixels
+    HighestResolution,
+    /// Anything under this directory, compared by canonical paths so any spelling of it works.
+    /// Parsing the rule canonicalizes it, and fails if there is no such directory.
+    Prefer(PathBuf),
+    /// Any path this pattern matches, matched against the path's raw bytes
+    Matching(Regex),
+}
+
+impl FromStr for KeepRule {
+    type Err = String;
+
+    fn from_str(spec: &str) -> Result<KeepRule, String> {
+        let (name, value) = match spec.split_once('=') {
+            Some((name, value)) => (name, Some(value)),
+            None => (spec, None),
+        };
+        match (name, value) {
+            ("oldest", None) => Ok(KeepRule::Oldest),
+            ("newest", None) => Ok(KeepRule::Newest),
+            ("shortest-path", None) => Ok(KeepRule::ShortestPath),
+            ("longest-path", None) => Ok(KeepRule::LongestPath),
+            ("largest", None) => Ok(KeepRule::Largest),
+            ("highest-resolution", None) => Ok(KeepRule::HighestResolution),
+            ("prefer", Some(dir)) if !dir.is_empty() => match fs::canonicalize(dir) {
+                Ok(canonical) if canonical.is_dir() => Ok(KeepRule::Prefer(canonical)),
+                Ok(_) => Err(format!("prefer={}: not a directory", dir)),
+                Err(e) => Err(format!("prefer={}: {}", dir, e)),
+            },
+            ("match", Some(pattern)) => Regex::new(pattern).map(KeepRule::Matching).map_err(|e| e.to_string()),
+            _ => Err(format!(
+                "unknown keep rule '{}', expected oldest, newest, shortest-path, longest-path, largest, \
+                 highest-resolution, prefer=DIR or match=REGEX",
+                spec
+            )),
+        }
+    }
+}
+
+impl fmt::Display for KeepRule {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KeepRule::Oldest => f.write_str("oldest"),
+            KeepRule::Newest => f.write_str("newest"),
+            KeepRule::ShortestPath => f.write_str("shortest-path"),
+            KeepRule::LongestPath => f.write_str("longest-path"),
+            KeepRule::Largest => f.write_str("largest"),
+            KeepRule::HighestResolution => f.write_str("highest-resolution"),
+            KeepRule::Prefer(dir) => write!(f, "prefer={}", dir.display()),
+            KeepRule::Matching(pattern) => write!(f, "match={}", pattern),
+        }
+    }
+}
+
+// What the rules need to know about one file in a group
+struct Candidate<'a> {
+    path: &'a Path,
+    // Only resolved when there is a prefer= rule, which compares canonical paths
+    canonical: PathBuf,
+    stamp: &'a FileStamp,
+    // Only looked up when a rule needs it, decoding images isn't cheap
+    pixels: u64,
+}
+
+// Prefer `true` over `false`
+fn prefer(a: bool, b: bool) -> Ordering {
+    b.cmp(&a)
+}
+
+// Order known values before unknown ones
+fn known_first<T: Ord>(a: Option<T>, b: Option<T>, order: impl Fn(&T, &T) -> Ordering) -> Ordering {
+    match (a, b) {
+        (Some(a), Some(b)) => order(&a, &b),
+        (Some(_), None) => Ordering::Less,
+        (None, Some(_)) => Ordering::Greater,
+        (None, None) => Ordering::Equal,
+    }
+}
+
+impl KeepRule {
+    // Less means `a` is the better file to keep
+    fn compare(&self, a: &Candidate, b: &Candidate) -> Ordering {
+        match self {
+            KeepRule::Oldest => known_first(a.stamp.modified, b.stamp.modified, Ord::cmp),
+            KeepRule::Newest => known_first(a.stamp.modified, b.stamp.modified, |a, b| b.cmp(a)),
+            KeepRule::ShortestPath => a.path.as_os_str().len().cmp(&b.path.as_os_str().len()),
+            KeepRule::LongestPath => b.path.as_os_str().len().cmp(&a.path.as_os_str().len()),
+            KeepRule::Largest => b.stamp.size.cmp(&a.stamp.size),
+            KeepRule::HighestResolution => b.pixels.cmp(&a.pixels),
+            KeepRule::Prefer(dir) => prefer(a.canonical.starts_with(dir), b.canonical.starts_with(dir)),
+            KeepRule::Matching(pattern) => {
+                prefer(pattern.is_match(a.path.as_os_str().as_encoded_bytes()), pattern.is_match(b.path.as_os_str().as_encoded_bytes()))
+            }
+        }
+    }
+}
+
+/// An ordered list of keep rules: each later rule only breaks ties left by the ones before it,
+/// and when every rule ties the file whose path sorts first is kept
+#[derive(Clone, Debug, Default)]
+pub struct KeepPolicy {
+    /// The rules, most important first
+    pub rules: Vec<KeepRule>,
+}
+
+impl fmt::Display for KeepPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.rules.is_empty() {
+            return f.write_str("first path");
+        }
+        let rules: Vec<String> = self.rules.iter().map(|rule| rule.to_string()).collect();
+        f.write_str(&rules.join(", "))
+    }
+}
+
+impl KeepPolicy {
+    fn needs_resolution(&self) -> bool {
+        self.rules.iter().any(|rule| matches!(rule, KeepRule::HighestResolution))
+    }
+
+    fn needs_canonical_paths(&self) -> bool {
+        self.rules.iter().any(|rule| matches!(rule, KeepRule::Prefer(_)))
+    }
+
+    // Index of the file to keep out of a group
+    pub(crate) fn choose(&self, files: &[FileEntry]) -> usize {
+        let candidates: Vec<Candidate> = files
+            .iter()
+            .map(|file| Candidate {
+                path: &file.path,
+                canonical: if self.needs_canonical_paths() {
+                    fs::canonicalize(&file.path).unwrap_or_else(|_| file.path.clone())
+                } else {
+                    PathBuf::new()
+                },
+                stamp: &file.stamp,
+                pixels: if self.needs_resolution() {
+                    // Undecodable images lose to any image we can measure
+                    image_dimensions(&file.path).map(|(width, height)| width as u64 * height as u64).unwrap_or(0)
+                } else {
+                    0
+                },
+            })
+            .collect();
+
+        // min_by keeps the earliest of equally good candidates, and groups arrive sorted by path
+        let (keep, _) = candidates
+            .iter()
+            .enumerate()
+            .min_by(|(_, a), (_, b)| {
+                self.rules.iter().map(|rule| rule.compare(a, b)).find(|o| o.is_ne()).unwrap_or(Ordering::Equal)
+            })
+            .expect("duplicate groups are never empty");
+        keep
+    }
+
+    /// Move the file to keep to the front of every group, which is where deletion expects it
+    pub fn apply(&self, groups: &mut [DuplicateGroup]) {
+        for group in groups {
+            let keep = self.choose(&group.files);
+            let keeper = group.files.remove(keep);
+            group.files.insert(0, keeper);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+    use std::time::{Duration, UNIX_EPOCH};
+
+    fn entry(path: PathBuf, size: u64, modified: Option<u64>) -> FileEntry {
+        let modified = modified.map(|seconds| UNIX_EPOCH + Duration::from_secs(seconds));
+        FileEntry { path, stamp: FileStamp { size, modified, inode: 0, dev: 0 }, reference: false }
+    }
+
+    fn policy(specs: &[&str]) -> KeepPolicy {
+        KeepPolicy { rules: specs.iter().map(|spec| spec.parse().unwrap()).collect() }
+    }
+
+    #[test]
+    fn rules_are_applied_in_order() {
+        let dir = TempDir::new();
+        let files = [
+            entry(dir.write("b/copy.jpg", b""), 10, Some(300)),
+            entry(dir.write("archive/photo.jpg", b""), 20, None),
+            entry(dir.write("a/photo.jpg", b""), 20, Some(100)),
+        ];
+        assert_eq!(policy(&[]).choose(&files), 0);
+        // A file without a modification time is never taken for the oldest or the newest
+        assert_eq!(policy(&["oldest"]).choose(&files), 2);
+        assert_eq!(policy(&["newest"]).choose(&files), 0);
+        assert_eq!(policy(&["largest"]).choose(&files), 1);
+        assert_eq!(policy(&["largest", "oldest"]).choose(&files), 2);
+        assert_eq!(policy(&["longest-path"]).choose(&files), 1);
+        assert_eq!(policy(&["match=photo", "newest"]).choose(&files), 2);
+
+        // Any spelling of the preferred folder matches
+        let prefer = format!("prefer={}", dir.path().join("b/../archive").display());
+        assert_eq!(policy(&[&prefer]).choose(&files), 1);
+        assert!("prefer=/no/such/folder".parse::<KeepRule>().is_err());
+        assert!("biggest".parse::<KeepRule>().is_err());
+    }
+}
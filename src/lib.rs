@@ -0,0 +1,43 @@
+//! Find duplicate images, either byte-for-byte copies or pictures that merely look alike,
+//! and decide which copy of each to keep.
+//!
+//! [`Scanner`] walks a set of folders and returns a [`ScanReport`] of [`DuplicateGroup`]s.
+//! The remaining modules turn a report into JSON, CSV or HTML ([`report`], [`html`]), plan
+//! and carry out the removal of duplicates ([`plan`]), and record what was done so it can
+//! be undone ([`journal`]).
+//!
+//! Some things only work on Unix-like systems: paths that aren't valid Unicode, telling hard
+//! links to one file apart from copies, the desktop trash and symbolic links.
+
+#![warn(missing_docs)]
+
+pub mod actions;
+pub mod apply;
+pub mod cache;
+pub mod config;
+mod decode;
+pub mod escape;
+pub mod filetype;
+pub mod hasher;
+pub mod html;
+pub mod journal;
+pub mod keep;
+pub mod perceptual;
+pub mod plan;
+mod raw;
+pub mod report;
+pub mod scan;
+mod scanner;
+#[cfg(test)]
+mod testing;
+mod trash;
+mod verify;
+
+pub use filetype::{Detection, ImageType};
+pub use hasher::HashAlgorithm;
+pub use keep::{KeepPolicy, KeepRule};
+pub use perceptual::PerceptualAlgorithm;
+pub use scan::{
+    DuplicateGroup, FileEntry, FileStamp, HashMode, ScanError, ScanErrorKind, ScanReport, ScanStats, TypeMismatch,
+};
+pub use scanner::Scanner;
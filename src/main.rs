@@ -1,8 +1,6 @@
-use std::collections::HashMap;
-use std::fs;
 use std::fs::File;
-use std::path::Path;
-use std::io::Read;
+use std::io::{BufWriter, Write};
+use std::path::PathBuf;
 // use std::ffi::OsStr;
 // use std::os::unix::ffi::OsStrExt; // Required for .as_bytes() on Unix-like systems
 // use opencv::prelude::*;
@@ -10,114 +8,282 @@ use std::io::Read;
 // use opencv::imgcodecs::imread;
 // use opencv::imgproc::resize;
 // use opencv::imgproc::COLOR_BGR2GRAY;
-use walkdir::WalkDir;
+use clap::{CommandFactory, Parser};
 // use opencv::types::VectorOfu8;
 
+mod cli;
 
-fn calculate_image_hash(image_path: &str) -> Result<String, Box<dyn std::error::Error>> {
-    // Open the image file
-    let mut file = File::open(image_path)?;
-    let mut buffer = Vec::new();
+use cli::{CacheAction, Cli, Command, ExecuteArgs, OutputFormat};
+use dupchecker::apply::{load_report, plan_from_report};
+use dupchecker::cache::{default_cache_path, HashCache};
+use dupchecker::escape::escape_path;
+use dupchecker::html::write_html_report;
+use dupchecker::journal::{default_journal_path, read_journal, undo, Journal};
+use dupchecker::plan::{format_bytes, Action, ActionPlan, FileResult, Outcome};
+use dupchecker::report::{write_delimited, Report};
+use dupchecker::{DuplicateGroup, HashAlgorithm, HashMode, PerceptualAlgorithm, ScanReport, Scanner};
 
-    // Read the file's contents into the buffer
-    file.read_to_end(&mut buffer)?;
-
-    // Calculate the MD5 hash of the file's contents
-    let hash = md5::compute(buffer);
+// Carry out a plan, journaling every file it touches, and say how it went
+fn delete_duplicates(plan: &ActionPlan, journal_path: Option<PathBuf>) -> Result<(), Box<dyn std::error::Error>> {
+    let journal_path = journal_path.or_else(default_journal_path).ok_or("No journal location, pass --journal")?;
+    let mut journal = Journal::create(&journal_path)?;
+    let summary = plan.execute(&mut journal);
+    for (path, result) in &summary.files {
+        match result {
+            FileResult::Done(Outcome::Deleted) => println!("{}: {}", plan.action.past_tense(), escape_path(path)),
+            FileResult::Done(Outcome::MovedTo(destination)) => {
+                println!("{}: {} -> {}", plan.action.past_tense(), escape_path(path), escape_path(destination))
+            }
+            FileResult::Done(Outcome::LinkedTo(keep, kind)) => {
+                println!("{}: {} -> {} ({})", plan.action.past_tense(), escape_path(path), escape_path(keep), kind)
+            }
+            FileResult::Refused(reason) => eprintln!("Refusing to {} {}: {}", plan.action.verb(), escape_path(path), reason),
+            FileResult::Failed(e) => eprintln!("Error handling {}: {}", escape_path(path), e),
+        }
+    }
+    if let Some(e) = &summary.journal_error {
+        eprintln!("Stopping, cannot write the journal {}: {}", escape_path(&journal.path), e);
+        if let Some((path, _)) = summary.files.last() {
+            eprintln!("{} was handled but is not in the journal.", escape_path(path));
+        }
+    }
+    if summary.refused > 0 {
+        eprintln!("{} file(s) failed verification and were left alone.", summary.refused);
+    }
+    if summary.failed > 0 {
+        eprintln!("{} file(s) could not be handled.", summary.failed);
+    }
+    println!(
+        "{} {} file(s), reclaiming {}.",
+        plan.action.past_tense(),
+        summary.removed,
+        format_bytes(summary.reclaimed_bytes)
+    );
+    let journal_path = escape_path(&journal_path);
+    println!("Journal written to {}, undo with: dupchecker undo {}", journal_path, journal_path);
+    Ok(())
+}
 
-    // Return the hash as a hexadecimal string
-    Ok(format!("{:x}", hash))
+// Show a plan, then dry-run it, or confirm and carry it out
+fn run_plan(plan: &ActionPlan, execute: ExecuteArgs) -> Result<(), Box<dyn std::error::Error>> {
+    plan.check().map_err(|reason| format!("Cannot {} duplicates: {}", plan.action.verb(), reason))?;
+    // Always show the plan, whether it is about to run or not
+    plan.write(&mut std::io::stdout())?;
+    if execute.dry_run {
+        println!("Dry run: no files were deleted.");
+    } else if execute.assume_yes || confirm(&format!("Do you want to {} the duplicate images?", plan.action.verb()))? {
+        delete_duplicates(plan, execute.journal)?;
+    } else {
+        println!("Duplicate images left alone.");
+    }
+    Ok(())
 }
 
-// Function to calculate the MD5 hash of an image
-fn find_duplicate_images(folder_path: &str) -> Result<HashMap<String, Vec<String>>, Box<dyn std::error::Error>> {
-    // Check if the folder exists
-    if !Path::new(folder_path).is_dir() {
-        return Err(format!("Folder not found at {}", folder_path).into());
-    }
-
-    // Get a list of image paths in the folder and subfolders
-    let mut image_paths: Vec<String> = Vec::new();
-    for entry in WalkDir::new(folder_path).into_iter().filter_map(|e| e.ok()) {
-        let path = entry.path();
-        if path.is_file() {
-            if let Some(extension) = path.extension() {
-                let extension_str = extension.to_str().unwrap_or("").to_lowercase();
-                if ["png", "jpg", "jpeg", "gif", "bmp"].contains(&extension_str.as_str()) {
-                    image_paths.push(path.to_string_lossy().to_string());
-                }
-            }
+// Write the duplicate groups as a plain text listing
+fn write_text_report(groups: &[DuplicateGroup], out: &mut dyn Write) -> std::io::Result<()> {
+    if groups.is_empty() {
+        writeln!(out, "No duplicate images found.")?;
+        return Ok(());
+    }
+
+    writeln!(out, "Duplicate images found:")?;
+    for group in groups {
+        writeln!(out, "Hash: {}", group.hash)?;
+        let reference = if group.keep().reference { ", reference" } else { "" };
+        writeln!(out, "  * {} (keep{})", escape_path(&group.keep().path), reference)?;
+        for file in group.duplicates() {
+            writeln!(out, "  - {}", escape_path(&file.path))?;
         }
     }
+    Ok(())
+}
 
-    if image_paths.is_empty() {
-        println!("No images found in folder: {}", folder_path);
-        return Ok(HashMap::new()); // Return an empty HashMap
+// Pass on what the scan warned about, and say so when it found nothing to compare at all
+fn print_warnings(report: &ScanReport) {
+    for warning in &report.warnings {
+        eprintln!("Warning: {}", warning);
     }
+    if report.stats.files_found == 0 {
+        let folders: Vec<String> = report.roots.iter().chain(&report.references).map(|folder| escape_path(folder)).collect();
+        eprintln!("No images found in folder: {}", folders.join(", "));
+    }
+}
 
-    // Calculate the hash for each image and store it in a HashMap
-    let mut image_hashes: HashMap<String, Vec<String>> = HashMap::new();
-    for image_path in image_paths {
-        let image_hash = calculate_image_hash(&image_path)?; // Use the ? operator
-        image_hashes.entry(image_hash).or_insert_with(Vec::new).push(image_path);
+// Tell the user which files the scan had to skip
+fn print_skipped(report: &ScanReport) {
+    if report.errors.is_empty() {
+        return;
     }
+    eprintln!("Skipped {} unreadable file(s):", report.errors.len());
+    for error in &report.errors {
+        eprintln!("  - {}", error);
+    }
+}
 
-    // Filter out entries that are not duplicates
-    let duplicate_images: HashMap<String, Vec<String>> = image_hashes
-        .into_iter()
-        .filter(|(_, paths)| paths.len() > 1)
-        .collect();
+// Point out images whose extension doesn't say what they are
+fn print_mismatches(report: &ScanReport) {
+    if report.mismatches.is_empty() {
+        return;
+    }
+    eprintln!("Found {} image(s) with a misleading extension:", report.mismatches.len());
+    for mismatch in &report.mismatches {
+        eprintln!("  - {}", mismatch);
+    }
+}
 
-    Ok(duplicate_images)
+// Ask a yes/no question on stdin
+fn confirm(question: &str) -> Result<bool, Box<dyn std::error::Error>> {
+    println!("{} (yes/no): ", question);
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(answer.trim().to_lowercase() == "yes")
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
+// The original prompt-driven session: ask for everything on stdin
+fn run_interactive() -> Result<(), Box<dyn std::error::Error>> {
     // Get the folder path from the user
     let mut folder_path = String::new();
     println!("Enter the path to the folder containing images: ");
     std::io::stdin().read_line(&mut folder_path)?;
-    folder_path = folder_path.trim().to_string();
+    let folder_path = PathBuf::from(folder_path.trim());
 
-    // Find the duplicate images
-    let duplicates = find_duplicate_images(&folder_path)?;
+    // Get the comparison mode from the user
+    let mut mode_answer = String::new();
+    println!("Hash mode (exact/ahash/dhash/phash) [exact]: ");
+    std::io::stdin().read_line(&mut mode_answer)?;
+    let mode = match mode_answer.trim().to_lowercase().as_str() {
+        "" | "exact" => HashMode::Exact { algorithm: HashAlgorithm::Md5 },
+        name => {
+            let algorithm = match name {
+                "ahash" => PerceptualAlgorithm::Average,
+                "dhash" => PerceptualAlgorithm::Difference,
+                "phash" => PerceptualAlgorithm::Dct,
+                _ => return Err(format!("Unknown hash mode: {}", name).into()),
+            };
 
-    // Print the results
-    if duplicates.is_empty() {
-        println!("No duplicate images found.");
-    } else {
-        println!("Duplicate images found:");
-        for (image_hash, image_paths) in &duplicates { // Use a reference to avoid moving
-            println!("Hash: {}", image_hash);
-            for image_path in image_paths {
-                println!("  - {}", image_path);
-            }
+            // Similar images differ by a few bits, identical ones by none
+            let mut threshold_answer = String::new();
+            println!("Maximum Hamming distance to treat as duplicates (0-64) [5]: ");
+            std::io::stdin().read_line(&mut threshold_answer)?;
+            let threshold = match threshold_answer.trim() {
+                "" => 5,
+                value => value.parse::<u32>()?,
+            };
+            HashMode::Perceptual { algorithm, threshold }
         }
+    };
+    let mut scanner = Scanner::new().root(folder_path).mode(mode);
+    if let Some(cache) = default_cache_path() {
+        scanner = scanner.cache(cache);
+    }
+
+    // Find the duplicate images
+    let report = scanner.scan()?;
+    print_warnings(&report);
 
+    // Print the results
+    write_text_report(&report.groups, &mut std::io::stdout())?;
+    print_skipped(&report);
+    print_mismatches(&report);
+    if !report.groups.is_empty() {
         // Optional: Delete duplicate images (use with caution!)
-        println!("Do you want to delete the duplicate images? (yes/no): ");
-        let mut delete_duplicates = String::new();
-        std::io::stdin().read_line(&mut delete_duplicates)?;
-        delete_duplicates = delete_duplicates.trim().to_lowercase();
-
-        if delete_duplicates == "yes" {
-            for (_, image_paths) in &duplicates { // Use a reference here as well
-                // Keep the first image, delete the rest
-                for image_path in image_paths.iter().skip(1) {
-                    // Use Path::new to convert the string to a Path
-                    if let Err(e) = fs::remove_file(Path::new(image_path)) {
-                        eprintln!("Error deleting {}: {}", image_path, e); // Use eprintln! for errors
-                    } else {
-                        println!("Deleted: {}", image_path);
-                    }
-                }
-            }
+        if confirm("Do you want to delete the duplicate images?")? {
+            let plan = ActionPlan::from_report(&report, Action::Delete);
+            delete_duplicates(&plan, None)?;
             println!("Duplicate images deleted.");
         } else {
             println!("Duplicate images not deleted.");
         }
     }
 
-    Ok(()) // Return Ok(()) to indicate success
+    Ok(())
 }
 
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let cli = Cli::parse();
+    if cli.interactive {
+        return run_interactive();
+    }
+
+    match cli.command {
+        Some(Command::Scan(args)) => {
+            let report = args.scanner()?.scan()?;
+            print_warnings(&report);
+            write_text_report(&report.groups, &mut std::io::stdout())?;
+            print_skipped(&report);
+            print_mismatches(&report);
+        }
+        Some(Command::Delete(args)) => {
+            let report = args.scan.scanner()?.scan()?;
+            print_warnings(&report);
+            print_skipped(&report);
+            print_mismatches(&report);
+            let plan = ActionPlan::from_report(&report, args.execute.action());
+            if plan.is_empty() {
+                println!("No duplicate images found.");
+                return Ok(());
+            }
 
+            println!("Keeping one file per group by: {}", report.keep_policy);
+            run_plan(&plan, args.execute)?;
+        }
+        Some(Command::Report(args)) => {
+            let report = args.scan.scanner()?.scan()?;
+            print_warnings(&report);
+            let mut out: Box<dyn Write> = match &args.output {
+                Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+                None => Box::new(std::io::stdout()),
+            };
+            match args.format {
+                OutputFormat::Text => write_text_report(&report.groups, &mut out)?,
+                OutputFormat::Json => Report::new(&report).write_json(&mut out)?,
+                OutputFormat::Csv => write_delimited(&report, ',', &mut out)?,
+                OutputFormat::Tsv => write_delimited(&report, '\t', &mut out)?,
+                OutputFormat::Html => write_html_report(&Report::new(&report), &mut out)?,
+            }
+            out.flush()?;
+            print_skipped(&report);
+            print_mismatches(&report);
+        }
+        Some(Command::Apply(args)) => {
+            let report = load_report(&args.report)?;
+            let (plan, refused) = plan_from_report(&report, args.execute.action());
+            for (path, reason) in &refused {
+                eprintln!("Refusing {}: {}", escape_path(path), reason);
+            }
+            if !refused.is_empty() {
+                eprintln!("{} file(s) were left alone, because they changed since the report or are in a reference folder.", refused.len());
+            }
+            if plan.is_empty() {
+                println!("Nothing in the report is left to remove.");
+                return Ok(());
+            }
+            run_plan(&plan, args.execute)?;
+        }
+        Some(Command::Cache(args)) => {
+            let cache_path = args.cache.or_else(default_cache_path).ok_or("No hash cache location, pass --cache")?;
+            let mut cache = HashCache::open(&cache_path)?;
+            match args.action {
+                CacheAction::Prune => println!("Removed {} stale cache entries.", cache.prune()?),
+                CacheAction::Clear => println!("Removed {} cache entries.", cache.clear()?),
+            }
+        }
+        Some(Command::Undo(args)) => {
+            let entries = read_journal(&args.journal)?;
+            let summary = undo(&entries);
+            for (path, result) in &summary.files {
+                match result {
+                    Ok(note) => println!("Restored: {}", note),
+                    Err(reason) => eprintln!("Cannot restore {}: {}", escape_path(path), reason),
+                }
+            }
+            if summary.unrestorable > 0 {
+                eprintln!("{} file(s) could not be restored.", summary.unrestorable);
+            }
+            println!("Restored {} file(s).", summary.restored);
+        }
+        None => Cli::command().print_help()?,
+    }
+
+    Ok(()) // Return Ok(()) to indicate success
+}
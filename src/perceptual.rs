@@ -0,0 +1,291 @@
+//! Perceptual hashes, which stay close for images that look alike.
+
+use std::collections::{HashMap, HashSet};
+use std::path::Path;
+
+use opencv::core::{self, Mat, Size};
+use opencv::imgcodecs::IMREAD_GRAYSCALE;
+use opencv::imgproc::{resize, INTER_AREA};
+use opencv::prelude::*;
+
+use crate::decode::read_image;
+
+// Bumped whenever decoding or hashing changes what hash an image gets, so hashes cached by
+// an older version are never reused. Version 2 decodes RAW files through their largest
+// preview rather than whatever JPEG they embed, lossless raw data included.
+const CACHE_VERSION: u32 = 2;
+
+/// The perceptual hash algorithms we know how to compute
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum PerceptualAlgorithm {
+    /// Average hash (aHash), the quickest
+    Average,
+    /// Difference hash (dHash)
+    Difference,
+    /// DCT hash (pHash), the slowest and the most tolerant of re-encoding
+    Dct,
+}
+
+impl PerceptualAlgorithm {
+    /// The name hashes are tagged with in reports
+    pub fn name(&self) -> &'static str {
+        match self {
+            PerceptualAlgorithm::Average => "ahash",
+            PerceptualAlgorithm::Difference => "dhash",
+            PerceptualAlgorithm::Dct => "phash",
+        }
+    }
+
+    // The kind hashes are cached under, e.g. "phash-v2"
+    pub(crate) fn cache_kind(&self) -> String {
+        format!("{}-v{}", self.name(), CACHE_VERSION)
+    }
+}
+
+// Shrink a grayscale image down to width x height, averaging away fine detail
+fn shrink(image: &Mat, width: i32, height: i32) -> Result<Mat, Box<dyn std::error::Error>> {
+    let mut small = Mat::default();
+    resize(image, &mut small, Size::new(width, height), 0.0, 0.0, INTER_AREA)?;
+    Ok(small)
+}
+
+// aHash: one bit per pixel of an 8x8 thumbnail, set when brighter than the mean
+fn average_hash(image: &Mat) -> Result<u64, Box<dyn std::error::Error>> {
+    let small = shrink(image, 8, 8)?;
+    let mut pixels = [0u8; 64];
+    for row in 0..8 {
+        for col in 0..8 {
+            pixels[(row * 8 + col) as usize] = *small.at_2d::<u8>(row, col)?;
+        }
+    }
+    let mean = pixels.iter().map(|&p| p as u32).sum::<u32>() / 64;
+
+    let mut hash = 0u64;
+    for (i, &pixel) in pixels.iter().enumerate() {
+        if pixel as u32 > mean {
+            hash |= 1 << i;
+        }
+    }
+    Ok(hash)
+}
+
+// dHash: one bit per horizontal neighbour pair of a 9x8 thumbnail, set when the gradient rises
+fn difference_hash(image: &Mat) -> Result<u64, Box<dyn std::error::Error>> {
+    let small = shrink(image, 9, 8)?;
+    let mut hash = 0u64;
+    for row in 0..8 {
+        for col in 0..8 {
+            let left = *small.at_2d::<u8>(row, col)?;
+            let right = *small.at_2d::<u8>(row, col + 1)?;
+            if left < right {
+                hash |= 1 << (row * 8 + col);
+            }
+        }
+    }
+    Ok(hash)
+}
+
+// pHash: the low 8x8 frequencies of a 32x32 DCT, set when above their median
+fn dct_hash(image: &Mat) -> Result<u64, Box<dyn std::error::Error>> {
+    let small = shrink(image, 32, 32)?;
+    let mut float = Mat::default();
+    small.convert_to(&mut float, core::CV_32F, 1.0, 0.0)?;
+    let mut frequencies = Mat::default();
+    core::dct(&float, &mut frequencies, 0)?;
+
+    let mut coefficients = [0f32; 64];
+    for row in 0..8 {
+        for col in 0..8 {
+            coefficients[(row * 8 + col) as usize] = *frequencies.at_2d::<f32>(row, col)?;
+        }
+    }
+
+    // Leave the DC term out of the median, it only reflects overall brightness
+    let mut sorted = coefficients[1..].to_vec();
+    sorted.sort_by(|a, b| a.total_cmp(b));
+    let median = sorted[sorted.len() / 2];
+
+    let mut hash = 0u64;
+    for (i, &coefficient) in coefficients.iter().enumerate() {
+        if coefficient > median {
+            hash |= 1 << i;
+        }
+    }
+    Ok(hash)
+}
+
+/// Compute the 64-bit perceptual hash of an image file
+pub fn perceptual_hash(image_path: &Path, algorithm: PerceptualAlgorithm) -> Result<u64, Box<dyn std::error::Error>> {
+    let image = read_image(image_path, IMREAD_GRAYSCALE)?;
+    match algorithm {
+        PerceptualAlgorithm::Average => average_hash(&image),
+        PerceptualAlgorithm::Difference => difference_hash(&image),
+        PerceptualAlgorithm::Dct => dct_hash(&image),
+    }
+}
+
+/// Number of bits that differ between two hashes
+pub fn hamming_distance(a: u64, b: u64) -> u32 {
+    (a ^ b).count_ones()
+}
+
+// A BK-tree over hashes so neighbour lookups don't have to compare every pair
+struct BkNode {
+    hash: u64,
+    index: usize,
+    children: HashMap<u32, BkNode>,
+}
+
+impl BkNode {
+    fn insert(&mut self, hash: u64, index: usize) {
+        let distance = hamming_distance(self.hash, hash);
+        match self.children.get_mut(&distance) {
+            Some(child) => child.insert(hash, index),
+            None => {
+                self.children.insert(distance, BkNode { hash, index, children: HashMap::new() });
+            }
+        }
+    }
+
+    fn find_within(&self, hash: u64, threshold: u32, found: &mut Vec<usize>) {
+        let distance = hamming_distance(self.hash, hash);
+        if distance <= threshold {
+            found.push(self.index);
+        }
+        // Triangle inequality: only subtrees in [distance - threshold, distance + threshold] can match
+        let low = distance.saturating_sub(threshold);
+        let high = distance + threshold;
+        for (&edge, child) in &self.children {
+            if edge >= low && edge <= high {
+                child.find_within(hash, threshold, found);
+            }
+        }
+    }
+}
+
+/// Group hashes whose Hamming distance is within `threshold` bits of each other.
+/// Every member of a group is within `threshold` of every other member, so whichever file
+/// is kept, nothing removed looks any further from it than that. Near matches don't chain:
+/// A~B and B~C don't put A and C together unless A~C too. Each hash joins the first group,
+/// in input order, it is close to all of. Returns the member indices of every group with
+/// more than one member.
+pub fn group_similar(hashes: &[u64], threshold: u32) -> Vec<Vec<usize>> {
+    let mut groups: Vec<Vec<usize>> = Vec::new();
+    let mut group_of: Vec<usize> = Vec::with_capacity(hashes.len());
+    let mut tree: Option<BkNode> = None;
+
+    for (index, &hash) in hashes.iter().enumerate() {
+        let mut neighbours = Vec::new();
+        if let Some(root) = &tree {
+            root.find_within(hash, threshold, &mut neighbours);
+        }
+        let neighbours: HashSet<usize> = neighbours.into_iter().collect();
+        let mut candidates: Vec<usize> = neighbours.iter().map(|&neighbour| group_of[neighbour]).collect();
+        candidates.sort_unstable();
+        candidates.dedup();
+        let joined = candidates.into_iter().find(|&group| groups[group].iter().all(|member| neighbours.contains(member)));
+        match joined {
+            Some(group) => {
+                groups[group].push(index);
+                group_of.push(group);
+            }
+            None => {
+                group_of.push(groups.len());
+                groups.push(vec![index]);
+            }
+        }
+
+        match &mut tree {
+            Some(root) => root.insert(hash, index),
+            None => tree = Some(BkNode { hash, index, children: HashMap::new() }),
+        }
+    }
+    groups.into_iter().filter(|members| members.len() > 1).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Groups in a fixed order, members ascending, so results can be compared
+    fn sorted(mut groups: Vec<Vec<usize>>) -> Vec<Vec<usize>> {
+        for group in &mut groups {
+            group.sort();
+        }
+        groups.sort();
+        groups
+    }
+
+    // The same grouping worked out by comparing each hash with every member of every group
+    fn group_by_every_pair(hashes: &[u64], threshold: u32) -> Vec<Vec<usize>> {
+        let mut groups: Vec<Vec<usize>> = Vec::new();
+        for (index, &hash) in hashes.iter().enumerate() {
+            let close = |group: &&mut Vec<usize>| group.iter().all(|&member| hamming_distance(hashes[member], hash) <= threshold);
+            match groups.iter_mut().find(close) {
+                Some(group) => group.push(index),
+                None => groups.push(vec![index]),
+            }
+        }
+        sorted(groups.into_iter().filter(|members| members.len() > 1).collect())
+    }
+
+    #[test]
+    fn cached_hashes_are_versioned() {
+        assert_eq!(PerceptualAlgorithm::Dct.cache_kind(), format!("phash-v{}", CACHE_VERSION));
+        assert_ne!(PerceptualAlgorithm::Dct.cache_kind(), PerceptualAlgorithm::Dct.name());
+    }
+
+    #[test]
+    fn hamming_distance_counts_differing_bits() {
+        assert_eq!(hamming_distance(0, 0), 0);
+        assert_eq!(hamming_distance(0b1011, 0b0010), 2);
+        assert_eq!(hamming_distance(0, u64::MAX), 64);
+    }
+
+    #[test]
+    fn only_close_hashes_are_grouped() {
+        let hashes = [0, 0b1, 0xff00, 0xff01, u64::MAX];
+        assert_eq!(sorted(group_similar(&hashes, 1)), vec![vec![0, 1], vec![2, 3]]);
+        assert_eq!(sorted(group_similar(&hashes, 0)), Vec::<Vec<usize>>::new());
+    }
+
+    #[test]
+    fn near_matches_do_not_chain() {
+        // Each hash is two bits from the next, so the ends are six bits apart and must never
+        // end up in one group, where either could be kept and the other removed
+        let hashes = [0, 0b11, 0b1111, 0b11_1111];
+        assert_eq!(sorted(group_similar(&hashes, 2)), vec![vec![0, 1], vec![2, 3]]);
+        assert_eq!(sorted(group_similar(&hashes, 4)), vec![vec![0, 1, 2]]);
+    }
+
+    #[test]
+    fn identical_hashes_are_grouped_at_threshold_zero() {
+        let hashes = [7, 9, 7, 7];
+        assert_eq!(sorted(group_similar(&hashes, 0)), vec![vec![0, 2, 3]]);
+    }
+
+    #[test]
+    fn matches_comparing_every_pair() {
+        // Hashes near a few centres, so there are groups to find at every threshold
+        let mut state: u64 = 0x9e37_79b9_7f4a_7c15;
+        let mut random = || {
+            state ^= state << 13;
+            state ^= state >> 7;
+            state ^= state << 17;
+            state
+        };
+        let centres = [random(), random(), random()];
+        let hashes: Vec<u64> = (0..200)
+            .map(|i| centres[i % 3] ^ (1 << (random() % 64)) ^ (1 << (random() % 64)) ^ (1 << (random() % 64)))
+            .collect();
+        for threshold in [0, 1, 2, 4, 6, 10] {
+            let groups = group_similar(&hashes, threshold);
+            for group in &groups {
+                for &a in group {
+                    assert!(group.iter().all(|&b| hamming_distance(hashes[a], hashes[b]) <= threshold), "threshold {}", threshold);
+                }
+            }
+            assert_eq!(sorted(groups), group_by_every_pair(&hashes, threshold), "threshold {}", threshold);
+        }
+    }
+}
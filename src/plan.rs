@@ -0,0 +1,411 @@
+//! Working out what removing duplicates will do, and then doing it.
+
+use std::ffi::{OsStr, OsString};
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Component, Path, PathBuf};
+
+use crate::actions::{self, LinkKind};
+use crate::escape::escape_path;
+use crate::journal::Journal;
+use crate::scan::{FileEntry, FileStamp, HashMode, ScanReport};
+use crate::{trash, verify};
+
+/// What happens to the duplicates that aren't kept
+#[derive(Clone, Debug)]
+pub enum Action {
+    /// Remove them for good
+    Delete,
+    /// Move them under this directory, keeping their paths relative to the scan root.
+    /// With several roots, each root's files go in a folder of their own.
+    Quarantine(PathBuf),
+    /// Move them to the freedesktop.org trash, where the desktop can restore them from
+    Trash,
+    /// Replace them with links to the kept file, so every path keeps working
+    Link(LinkKind),
+}
+
+impl Action {
+    /// The action as a verb, as the plan lists it: "delete", "move", "trash" or "link"
+    pub fn verb(&self) -> &'static str {
+        match self {
+            Action::Delete => "delete",
+            Action::Quarantine(_) => "move",
+            Action::Trash => "trash",
+            Action::Link(_) => "link",
+        }
+    }
+
+    /// What is printed for each file handled, e.g. "Deleted"
+    pub fn past_tense(&self) -> &'static str {
+        match self {
+            Action::Delete => "Deleted",
+            Action::Quarantine(_) => "Moved",
+            Action::Trash => "Trashed",
+            Action::Link(_) => "Linked",
+        }
+    }
+}
+
+/// A file the plan acts on, as it looked when it was hashed
+#[derive(Clone, Debug)]
+pub struct PlannedFile {
+    /// Where the file is
+    pub path: PathBuf,
+    /// How it looked, which it must still look like when the plan gets to it
+    pub stamp: FileStamp,
+}
+
+/// What happens to one duplicate group
+#[derive(Clone, Debug)]
+pub struct GroupPlan {
+    /// The hash the group's files share, as in [`DuplicateGroup::hash`](crate::DuplicateGroup::hash)
+    pub hash: String,
+    /// The file left alone, which every removed file is checked against
+    pub keep: PlannedFile,
+    /// The files the action is done to
+    pub remove: Vec<PlannedFile>,
+}
+
+impl GroupPlan {
+    /// Bytes freed once every duplicate in the group is gone
+    pub fn reclaimable_bytes(&self) -> u64 {
+        self.remove.iter().map(|file| file.stamp.size).sum()
+    }
+}
+
+/// Everything a deletion will do, worked out up front. A dry run prints this plan and a real
+/// run executes the very same one, so the two can't disagree.
+#[derive(Clone, Debug)]
+pub struct ActionPlan {
+    /// What is done to each duplicate
+    pub action: Action,
+    /// The folders that were scanned, which quarantined paths are made relative to
+    pub roots: Vec<PathBuf>,
+    /// Read-only folders: nothing under them is ever removed, whatever the groups say
+    pub references: Vec<PathBuf>,
+    /// The groups with something to remove, in the order they are worked through
+    pub groups: Vec<GroupPlan>,
+    /// Require byte-for-byte equality with the kept file, not just an unchanged file.
+    /// Perceptual groups are similar rather than identical, so they can't be held to that.
+    pub compare_contents: bool,
+}
+
+/// What became of one file the plan acted on
+#[derive(Clone, Debug)]
+pub enum Outcome {
+    /// Removed for good
+    Deleted,
+    /// Moved here, into the quarantine folder or the trash
+    MovedTo(PathBuf),
+    /// Replaced with a link of this kind to the kept file at this path
+    LinkedTo(PathBuf, LinkKind),
+}
+
+/// What carrying out a plan did to one file
+#[derive(Clone, Debug)]
+pub enum FileResult {
+    /// The action was done
+    Done(Outcome),
+    /// The file was left alone, for this reason
+    Refused(String),
+    /// The action failed with this error
+    Failed(String),
+}
+
+/// What executing a plan actually did
+#[derive(Default)]
+pub struct ExecutionSummary {
+    /// Files the action was done to
+    pub removed: usize,
+    /// Their total size
+    pub reclaimed_bytes: u64,
+    /// Files left alone because they changed since the scan, no longer match the kept file,
+    /// lie in a reference folder or can't take the plan's action
+    pub refused: usize,
+    /// Files the action failed on
+    pub failed: usize,
+    /// Every file the plan got to, in the order it got to them, and what became of each
+    pub files: Vec<(PathBuf, FileResult)>,
+    /// Why the run stopped early: the journal couldn't be written after the last file in
+    /// `files` was handled, so that file is missing from it
+    pub journal_error: Option<String>,
+}
+
+/// Sizes in the units people read them in
+pub fn format_bytes(bytes: u64) -> String {
+    const UNITS: [&str; 5] = ["B", "KiB", "MiB", "GiB", "TiB"];
+    let mut value = bytes as f64;
+    let mut unit = 0;
+    while value >= 1024.0 && unit < UNITS.len() - 1 {
+        value /= 1024.0;
+        unit += 1;
+    }
+    if unit == 0 {
+        format!("{} B", bytes)
+    } else {
+        format!("{:.1} {}", value, UNITS[unit])
+    }
+}
+
+impl ActionPlan {
+    /// Keep the first file of every group, which the keep policy has already chosen, and remove the rest
+    pub fn from_report(report: &ScanReport, action: Action) -> ActionPlan {
+        let planned = |file: &FileEntry| PlannedFile { path: file.path.clone(), stamp: file.stamp };
+        let groups = report
+            .groups
+            .iter()
+            .map(|group| GroupPlan {
+                hash: group.hash.clone(),
+                keep: planned(group.keep()),
+                remove: group.duplicates().iter().map(planned).collect(),
+            })
+            .collect();
+
+        ActionPlan {
+            action,
+            roots: report.roots.clone(),
+            references: report.references.clone(),
+            groups,
+            compare_contents: matches!(report.mode, HashMode::Exact { .. }),
+        }
+    }
+
+    // Whether a path lies under one of the reference folders, comparing canonical paths
+    // so neither relative spellings nor symlinked folders can slip past
+    fn in_reference(&self, path: &Path) -> bool {
+        let canonical = |path: &Path| fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+        let path = canonical(path);
+        self.references.iter().any(|root| path.starts_with(canonical(root)))
+    }
+
+    // The folder a root's files are quarantined in when there are several roots, so files with
+    // the same path below different roots can't collide: the root's own name, numbered when
+    // another root has the same name
+    fn root_folder(&self, index: usize) -> OsString {
+        let name = |root: &Path| {
+            let root = fs::canonicalize(root).unwrap_or_else(|_| root.to_path_buf());
+            root.file_name().map(OsStr::to_os_string).unwrap_or_else(|| OsString::from("root"))
+        };
+        let own = name(&self.roots[index]);
+        let shared = self.roots.iter().enumerate().any(|(other, root)| other != index && name(root) == own);
+        if !shared {
+            return own;
+        }
+        let mut numbered = OsString::from(format!("{}-", index + 1));
+        numbered.push(own);
+        numbered
+    }
+
+    // Where a quarantined file ends up: its path below the scan root it was found in,
+    // re-rooted under the quarantine directory
+    fn quarantine_path(&self, quarantine: &Path, path: &Path) -> PathBuf {
+        let mut destination = quarantine.to_path_buf();
+        let found = self
+            .roots
+            .iter()
+            .enumerate()
+            .filter_map(|(index, root)| Some((index, path.strip_prefix(root).ok()?)))
+            .min_by_key(|(_, relative)| relative.components().count());
+        let relative = match found {
+            Some((index, relative)) => {
+                if self.roots.len() > 1 {
+                    destination.push(self.root_folder(index));
+                }
+                relative
+            }
+            None => path,
+        };
+
+        // Never let an absolute or `..` path escape the quarantine directory
+        destination.extend(relative.components().filter(|component| matches!(component, Component::Normal(_))));
+        destination
+    }
+
+    /// Where a removed file goes, or for links what it will point at, if that is known up front.
+    /// The trash picks a free name at the moment the file is moved in.
+    pub fn destination(&self, group: &GroupPlan, file: &PlannedFile) -> Option<PathBuf> {
+        match &self.action {
+            Action::Delete | Action::Trash => None,
+            Action::Quarantine(quarantine) => Some(self.quarantine_path(quarantine, &file.path)),
+            Action::Link(_) => Some(group.keep.path.clone()),
+        }
+    }
+
+    // Do the plan's action to one file
+    fn perform(&self, group: &GroupPlan, file: &PlannedFile) -> io::Result<Outcome> {
+        let source = file.path.as_path();
+        match &self.action {
+            Action::Delete => fs::remove_file(source).map(|()| Outcome::Deleted),
+            Action::Quarantine(quarantine) => {
+                let destination = self.quarantine_path(quarantine, &file.path);
+                actions::move_file(source, &destination).map(|()| Outcome::MovedTo(destination))
+            }
+            Action::Trash => trash::trash_file(source).map(Outcome::MovedTo),
+            Action::Link(kind) => {
+                let keep = group.keep.path.clone();
+                let made = actions::link_file(&keep, source, *kind)?;
+                Ok(Outcome::LinkedTo(keep, made))
+            }
+        }
+    }
+
+    /// Make sure the action can be done to the plan's groups. Only identical files may be
+    /// replaced with links: a perceptual group's files are merely similar, and linking one to
+    /// the kept file would throw away its own pixels, which undo couldn't bring back.
+    pub fn check(&self) -> Result<(), String> {
+        if matches!(self.action, Action::Link(_)) && !self.compare_contents {
+            return Err("only identical files can be replaced with links, and perceptual groups are merely similar".to_string());
+        }
+        Ok(())
+    }
+
+    /// Whether there is nothing to do
+    pub fn is_empty(&self) -> bool {
+        self.groups.is_empty()
+    }
+
+    /// How many files the action is done to
+    pub fn file_count(&self) -> usize {
+        self.groups.iter().map(|group| group.remove.len()).sum()
+    }
+
+    /// Bytes freed once the whole plan is carried out
+    pub fn reclaimable_bytes(&self) -> u64 {
+        self.groups.iter().map(GroupPlan::reclaimable_bytes).sum()
+    }
+
+    /// Print the plan without touching anything
+    pub fn write(&self, out: &mut dyn Write) -> io::Result<()> {
+        for group in &self.groups {
+            writeln!(out, "Group {}:", group.hash)?;
+            writeln!(out, "  keep   {} ({})", escape_path(&group.keep.path), format_bytes(group.keep.stamp.size))?;
+            for file in &group.remove {
+                write!(out, "  {:<6} {} ({})", self.action.verb(), escape_path(&file.path), format_bytes(file.stamp.size))?;
+                match self.destination(group, file) {
+                    Some(destination) => writeln!(out, " -> {}", escape_path(&destination))?,
+                    None => writeln!(out)?,
+                }
+            }
+            writeln!(out, "  reclaims {}", format_bytes(group.reclaimable_bytes()))?;
+        }
+        writeln!(
+            out,
+            "Total: {} file(s) to {} in {} group(s), reclaiming {}",
+            self.file_count(),
+            self.action.verb(),
+            self.groups.len(),
+            format_bytes(self.reclaimable_bytes())
+        )
+    }
+
+    /// Carry out the plan, re-checking each duplicate against its kept file right before removing it
+    /// and recording everything done in the journal. Stops as soon as the journal can't be written,
+    /// so at most one file is ever handled without undo knowing about it. Prints nothing: what
+    /// became of each file is in the summary.
+    pub fn execute(&self, journal: &mut Journal) -> ExecutionSummary {
+        let mut summary = ExecutionSummary::default();
+        let refusal = self.check().err();
+        'groups: for group in &self.groups {
+            for file in &group.remove {
+                let refused = match &refusal {
+                    Some(reason) => Err(reason.clone()),
+                    None if self.in_reference(&file.path) => Err("it is in a reference folder".to_string()),
+                    None => verify::verify_duplicate(&group.keep, file, self.compare_contents),
+                };
+                if let Err(reason) = refused {
+                    summary.refused += 1;
+                    summary.files.push((file.path.clone(), FileResult::Refused(reason)));
+                    continue;
+                }
+
+                let outcome = match self.perform(group, file) {
+                    Err(e) => {
+                        summary.failed += 1;
+                        summary.files.push((file.path.clone(), FileResult::Failed(e.to_string())));
+                        continue;
+                    }
+                    Ok(outcome) => outcome,
+                };
+                summary.removed += 1;
+                summary.reclaimed_bytes += file.stamp.size;
+                let recorded = journal.record(&self.action, &group.hash, file, &outcome);
+                summary.files.push((file.path.clone(), FileResult::Done(outcome)));
+                if let Err(e) = recorded {
+                    summary.journal_error = Some(e.to_string());
+                    break 'groups;
+                }
+            }
+        }
+        summary
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn planned(path: PathBuf) -> PlannedFile {
+        let stamp = FileStamp::of(&path).unwrap();
+        PlannedFile { path, stamp }
+    }
+
+    #[test]
+    fn similar_images_are_never_linked() {
+        let dir = TempDir::new();
+        let keep = planned(dir.write("keep.jpg", b"one picture"));
+        let similar = planned(dir.write("similar.jpg", b"a picture much like it"));
+        let plan = ActionPlan {
+            action: Action::Link(LinkKind::Hard),
+            roots: vec![dir.path().to_path_buf()],
+            references: Vec::new(),
+            groups: vec![GroupPlan { hash: "phash:0".to_string(), keep, remove: vec![similar] }],
+            compare_contents: false,
+        };
+        assert!(plan.check().is_err());
+
+        let mut journal = Journal::create(&dir.path().join("journal.tsv")).unwrap();
+        let summary = plan.execute(&mut journal);
+        assert_eq!((summary.removed, summary.refused), (0, 1));
+        assert_eq!(fs::read(dir.path().join("similar.jpg")).unwrap(), b"a picture much like it");
+
+        // Every other action leaves a copy to restore from
+        for action in [Action::Delete, Action::Trash, Action::Quarantine(dir.path().join("q"))] {
+            assert!(ActionPlan { action, ..plan.clone() }.check().is_ok());
+        }
+    }
+
+    #[test]
+    fn quarantined_files_keep_their_place_below_their_root() {
+        let dir = TempDir::new();
+        let root = |relative: &str| {
+            let path = dir.path().join(relative);
+            fs::create_dir_all(&path).unwrap();
+            path
+        };
+        let quarantine = dir.path().join("q");
+        let plan = |roots: Vec<PathBuf>| ActionPlan {
+            action: Action::Quarantine(quarantine.clone()),
+            roots,
+            references: Vec::new(),
+            groups: Vec::new(),
+            compare_contents: true,
+        };
+
+        let photos = root("a/photos");
+        let single = plan(vec![photos.clone()]);
+        assert_eq!(single.quarantine_path(&quarantine, &photos.join("2020/x.jpg")), quarantine.join("2020/x.jpg"));
+
+        // Roots with the same name are numbered, and a file goes by the deepest root it is under
+        let nested = root("a/photos/2020");
+        let other = root("b/photos");
+        let several = plan(vec![photos.clone(), other.clone(), nested.clone()]);
+        assert_eq!(several.quarantine_path(&quarantine, &other.join("x.jpg")), quarantine.join("2-photos/x.jpg"));
+        assert_eq!(several.quarantine_path(&quarantine, &nested.join("x.jpg")), quarantine.join("2020/x.jpg"));
+        assert_eq!(several.quarantine_path(&quarantine, &photos.join("y/x.jpg")), quarantine.join("1-photos/y/x.jpg"));
+
+        // Nothing outside the roots gets out of the quarantine folder
+        assert_eq!(single.quarantine_path(&quarantine, Path::new("/../etc/x.jpg")), quarantine.join("etc/x.jpg"));
+    }
+}
@@ -0,0 +1,404 @@
+use std::collections::HashSet;
+
+use crate::filetype::ImageType;
+
+// TIFF tags that point at an embedded JPEG, one way or another
+const TAG_COMPRESSION: u16 = 0x0103;
+const TAG_STRIP_OFFSETS: u16 = 0x0111;
+const TAG_STRIP_BYTE_COUNTS: u16 = 0x0117;
+const TAG_SUB_IFDS: u16 = 0x014a;
+const TAG_JPEG_OFFSET: u16 = 0x0201;
+const TAG_JPEG_LENGTH: u16 = 0x0202;
+const TAG_EXIF_IFD: u16 = 0x8769;
+
+// Compression values meaning a strip holds a JPEG
+const COMPRESSION_OLD_JPEG: u32 = 6;
+const COMPRESSION_JPEG: u32 = 7;
+
+// No real file has more image directories than this, so anything past it is a loop
+const MAX_IFDS: usize = 64;
+
+// A TIFF file's bytes, read with the byte order its header declares
+struct Tiff<'a> {
+    data: &'a [u8],
+    little_endian: bool,
+}
+
+// One 12-byte directory entry
+struct Entry {
+    tag: u16,
+    field_type: u16,
+    count: u32,
+    // Where the value is: inside the entry itself when it fits in four bytes, elsewhere otherwise
+    value_offset: usize,
+}
+
+impl Tiff<'_> {
+    fn u16_at(&self, offset: usize) -> Option<u16> {
+        let bytes = [*self.data.get(offset)?, *self.data.get(offset + 1)?];
+        Some(if self.little_endian { u16::from_le_bytes(bytes) } else { u16::from_be_bytes(bytes) })
+    }
+
+    fn u32_at(&self, offset: usize) -> Option<u32> {
+        let bytes = self.data.get(offset..offset + 4)?.try_into().ok()?;
+        Some(if self.little_endian { u32::from_le_bytes(bytes) } else { u32::from_be_bytes(bytes) })
+    }
+
+    // The entries of the directory at `offset`, and the offset of the next directory in the chain
+    fn directory(&self, offset: usize) -> Option<(Vec<Entry>, usize)> {
+        let count = self.u16_at(offset)? as usize;
+        let mut entries = Vec::with_capacity(count);
+        for index in 0..count {
+            let entry = offset + 2 + index * 12;
+            let field_type = self.u16_at(entry + 2)?;
+            let count = self.u32_at(entry + 4)?;
+            let size = match field_type {
+                3 => 2,      // SHORT
+                4 | 13 => 4, // LONG, IFD
+                _ => 1,
+            } * count as usize;
+            let value_offset = if size <= 4 { entry + 8 } else { self.u32_at(entry + 8)? as usize };
+            entries.push(Entry { tag: self.u16_at(entry)?, field_type, count, value_offset });
+        }
+        let next = self.u32_at(offset + 2 + count * 12).unwrap_or(0) as usize;
+        Some((entries, next))
+    }
+
+    // The `index`th value of an entry holding SHORTs or LONGs
+    fn value(&self, entry: &Entry, index: usize) -> Option<u32> {
+        match entry.field_type {
+            3 => self.u16_at(entry.value_offset + index * 2).map(u32::from),
+            4 | 13 => self.u32_at(entry.value_offset + index * 4),
+            _ => None,
+        }
+    }
+
+    // Every JPEG the directories point at, as offset and length. Walks the chain of main
+    // directories and the sub-directories and EXIF directory hanging off them, which is
+    // where cameras keep their full-size previews.
+    fn jpegs(&self) -> Vec<(usize, usize)> {
+        let mut jpegs = Vec::new();
+        let mut pending: Vec<usize> = self.u32_at(4).map(|first| first as usize).into_iter().collect();
+        let mut visited = HashSet::new();
+        while let Some(offset) = pending.pop() {
+            if offset == 0 || visited.len() >= MAX_IFDS || !visited.insert(offset) {
+                continue;
+            }
+            let Some((entries, next)) = self.directory(offset) else {
+                continue;
+            };
+            pending.push(next);
+
+            let find = |tag: u16| entries.iter().find(|entry| entry.tag == tag);
+            let single = |tag: u16| find(tag).filter(|entry| entry.count == 1).and_then(|entry| self.value(entry, 0));
+            if let (Some(start), Some(length)) = (single(TAG_JPEG_OFFSET), single(TAG_JPEG_LENGTH)) {
+                jpegs.push((start as usize, length as usize));
+            }
+            if matches!(single(TAG_COMPRESSION), Some(COMPRESSION_OLD_JPEG | COMPRESSION_JPEG))
+                && let (Some(start), Some(length)) = (single(TAG_STRIP_OFFSETS), single(TAG_STRIP_BYTE_COUNTS))
+            {
+                jpegs.push((start as usize, length as usize));
+            }
+            for tag in [TAG_SUB_IFDS, TAG_EXIF_IFD] {
+                if let Some(entry) = find(tag) {
+                    // The count comes from the file, so stop at the first value past its end
+                    let offsets = (0..entry.count as usize).take(MAX_IFDS).map_while(|index| self.value(entry, index));
+                    pending.extend(offsets.map(|offset| offset as usize));
+                }
+            }
+        }
+        jpegs
+    }
+}
+
+// The start-of-frame marker of a JPEG, found by walking its segments up to the start of scan.
+// It tells how the image is coded: 0xC0 is baseline, 0xC2 progressive, 0xC3 lossless, and so on.
+fn jpeg_frame(jpeg: &[u8]) -> Option<u8> {
+    let mut offset = 2;
+    loop {
+        if *jpeg.get(offset)? != 0xff {
+            return None;
+        }
+        let marker = *jpeg.get(offset + 1)?;
+        match marker {
+            // Fill bytes may pad any marker
+            0xff => offset += 1,
+            // Markers that stand alone, without a length
+            0x01 | 0xd0..=0xd8 => offset += 2,
+            // Start of scan: the coded data follows, and no frame was found before it
+            0xda => return None,
+            // SOF0 to SOF15, apart from DHT, JPG and DAC, which share the range
+            0xc0..=0xcf if !matches!(marker, 0xc4 | 0xc8 | 0xcc) => return Some(marker),
+            _ => {
+                let length = u16::from_be_bytes([*jpeg.get(offset + 2)?, *jpeg.get(offset + 3)?]);
+                offset += 2 + length as usize;
+            }
+        }
+    }
+}
+
+// The biggest JPEG a TIFF-based RAW file embeds that opencv can decode. Cameras also store
+// the raw sensor data itself as lossless JPEG, which is often the biggest of all, so only
+// baseline, extended and progressive JPEGs count.
+fn tiff_preview(data: &[u8]) -> Option<&[u8]> {
+    let little_endian = match data.get(..2)? {
+        b"II" => true,
+        b"MM" => false,
+        _ => return None,
+    };
+    let tiff = Tiff { data, little_endian };
+    tiff.jpegs()
+        .into_iter()
+        .filter_map(|(start, length)| data.get(start..start.checked_add(length)?))
+        .filter(|jpeg| jpeg.starts_with(&[0xff, 0xd8]) && matches!(jpeg_frame(jpeg), Some(0xc0..=0xc2)))
+        .max_by_key(|jpeg| jpeg.len())
+}
+
+// Fujifilm keeps the offset and length of a full JPEG at fixed places in its header
+fn raf_preview(data: &[u8]) -> Option<&[u8]> {
+    let read = |offset: usize| Some(u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?) as usize);
+    let (start, length) = (read(84)?, read(88)?);
+    data.get(start..start.checked_add(length)?)
+}
+
+// Canon keeps a preview JPEG in a box tagged PRVW: after the box header come 16 bytes of
+// dimensions and such, the last four of which are the JPEG's length
+fn cr3_preview(data: &[u8]) -> Option<&[u8]> {
+    let tag = data.windows(4).position(|window| window == b"PRVW")?;
+    let length = u32::from_be_bytes(data.get(tag + 16..tag + 20)?.try_into().ok()?) as usize;
+    let start = tag + 20;
+    data.get(start..start.checked_add(length)?)
+}
+
+// The JPEG preview a camera embeds in a RAW file, which decodes like any other image and is
+// what lets a RAW be matched with the JPEG the camera saved alongside it
+pub fn embedded_preview(data: &[u8], image_type: ImageType) -> Option<&[u8]> {
+    let preview = match image_type {
+        ImageType::Raf => raf_preview(data),
+        ImageType::Cr3 => cr3_preview(data),
+        _ => tiff_preview(data),
+    }?;
+    preview.starts_with(&[0xff, 0xd8]).then_some(preview)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SHORT: u16 = 3;
+    const LONG: u16 = 4;
+    const IFD: u16 = 13;
+
+    // A JPEG coded with the given start-of-frame marker, padded out to roughly `size` bytes
+    fn jpeg(frame: u8, size: usize) -> Vec<u8> {
+        let padding = size.saturating_sub(16);
+        let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe0];
+        jpeg.extend((2 + padding as u16).to_be_bytes());
+        jpeg.resize(jpeg.len() + padding, 0);
+        jpeg.extend([0xff, 0xc4, 0, 2, 0xff, frame, 0, 2, 0xff, 0xda, 0, 2, 0xff, 0xd9]);
+        jpeg
+    }
+
+    // Builds a TIFF file one directory or blob at a time
+    struct TiffWriter {
+        little_endian: bool,
+        data: Vec<u8>,
+    }
+
+    impl TiffWriter {
+        fn new(little_endian: bool) -> TiffWriter {
+            let header: &[u8] = if little_endian { b"II*\0\0\0\0\0" } else { b"MM\0*\0\0\0\0" };
+            TiffWriter { little_endian, data: header.to_vec() }
+        }
+
+        fn u16(&self, value: u16) -> [u8; 2] {
+            if self.little_endian { value.to_le_bytes() } else { value.to_be_bytes() }
+        }
+
+        fn u32(&self, value: u32) -> [u8; 4] {
+            if self.little_endian { value.to_le_bytes() } else { value.to_be_bytes() }
+        }
+
+        fn blob(&mut self, bytes: &[u8]) -> u32 {
+            let offset = self.data.len() as u32;
+            self.data.extend(bytes);
+            offset
+        }
+
+        fn longs(&mut self, values: &[u32]) -> u32 {
+            let bytes: Vec<u8> = values.iter().flat_map(|&value| self.u32(value)).collect();
+            self.blob(&bytes)
+        }
+
+        // Entries are (tag, type, count, value), the value being the value itself when it fits
+        // in four bytes and the offset of the values otherwise
+        fn ifd(&mut self, entries: &[(u16, u16, u32, u32)], next: u32) -> u32 {
+            let mut bytes = self.u16(entries.len() as u16).to_vec();
+            for &(tag, field_type, count, value) in entries {
+                bytes.extend(self.u16(tag));
+                bytes.extend(self.u16(field_type));
+                bytes.extend(self.u32(count));
+                if field_type == SHORT && count == 1 {
+                    bytes.extend(self.u16(value as u16));
+                    bytes.extend([0, 0]);
+                } else {
+                    bytes.extend(self.u32(value));
+                }
+            }
+            bytes.extend(self.u32(next));
+            self.blob(&bytes)
+        }
+
+        fn first(&mut self, offset: u32) -> Vec<u8> {
+            let offset = self.u32(offset);
+            self.data[4..8].copy_from_slice(&offset);
+            self.data.clone()
+        }
+    }
+
+    // A directory pointing at a JPEG through the JPEGInterchangeFormat tags
+    fn interchange(tiff: &mut TiffWriter, jpeg: &[u8], next: u32) -> u32 {
+        let start = tiff.blob(jpeg);
+        tiff.ifd(&[(TAG_JPEG_OFFSET, LONG, 1, start), (TAG_JPEG_LENGTH, LONG, 1, jpeg.len() as u32)], next)
+    }
+
+    // A directory holding a JPEG as its single strip
+    fn strip(tiff: &mut TiffWriter, jpeg: &[u8], compression: u32, next: u32) -> u32 {
+        let start = tiff.blob(jpeg);
+        tiff.ifd(
+            &[
+                (TAG_COMPRESSION, SHORT, 1, compression),
+                (TAG_STRIP_OFFSETS, LONG, 1, start),
+                (TAG_STRIP_BYTE_COUNTS, LONG, 1, jpeg.len() as u32),
+            ],
+            next,
+        )
+    }
+
+    #[test]
+    fn frames_are_found_past_other_segments() {
+        assert_eq!(jpeg_frame(&jpeg(0xc0, 100)), Some(0xc0));
+        assert_eq!(jpeg_frame(&jpeg(0xc2, 16)), Some(0xc2));
+        assert_eq!(jpeg_frame(&jpeg(0xc3, 16)), Some(0xc3));
+        // Fill bytes and markers without a length
+        assert_eq!(jpeg_frame(&[0xff, 0xd8, 0xff, 0xff, 0xd0, 0xff, 0xc1, 0, 2]), Some(0xc1));
+        // A scan without a frame, and data that isn't a JPEG at all
+        assert_eq!(jpeg_frame(&[0xff, 0xd8, 0xff, 0xda, 0, 2]), None);
+        assert_eq!(jpeg_frame(&[0xff, 0xd8, 0x00]), None);
+        assert_eq!(jpeg_frame(&[0xff, 0xd8, 0xff, 0xe1, 0xff, 0xff]), None);
+    }
+
+    #[test]
+    fn preview_in_a_sub_directory() {
+        let preview = jpeg(0xc0, 300);
+        let mut tiff = TiffWriter::new(true);
+        let sub = interchange(&mut tiff, &preview, 0);
+        let main = tiff.ifd(&[(TAG_SUB_IFDS, IFD, 1, sub)], 0);
+        let data = tiff.first(main);
+        assert_eq!(embedded_preview(&data, ImageType::Nef), Some(&preview[..]));
+    }
+
+    #[test]
+    fn preview_in_a_big_endian_strip() {
+        let preview = jpeg(0xc0, 300);
+        let mut tiff = TiffWriter::new(false);
+        let sub = strip(&mut tiff, &preview, COMPRESSION_JPEG, 0);
+        let subs = tiff.longs(&[sub, 0]);
+        let main = tiff.ifd(&[(TAG_SUB_IFDS, LONG, 2, subs)], 0);
+        let data = tiff.first(main);
+        assert_eq!(embedded_preview(&data, ImageType::Dng), Some(&preview[..]));
+    }
+
+    #[test]
+    fn lossless_raw_data_is_passed_over() {
+        // Laid out like a CR2: a small preview in the first directory and the raw data, a much
+        // bigger lossless JPEG, in the last
+        let preview = jpeg(0xc0, 300);
+        let raw = jpeg(0xc3, 5000);
+        let mut tiff = TiffWriter::new(true);
+        let last = strip(&mut tiff, &raw, COMPRESSION_OLD_JPEG, 0);
+        let first = strip(&mut tiff, &preview, COMPRESSION_OLD_JPEG, last);
+        let data = tiff.first(first);
+        assert_eq!(embedded_preview(&data, ImageType::Cr2), Some(&preview[..]));
+
+        let mut tiff = TiffWriter::new(true);
+        let only = strip(&mut tiff, &raw, COMPRESSION_JPEG, 0);
+        let data = tiff.first(only);
+        assert_eq!(embedded_preview(&data, ImageType::Dng), None);
+    }
+
+    #[test]
+    fn the_biggest_preview_wins() {
+        let thumbnail = jpeg(0xc0, 100);
+        let preview = jpeg(0xc2, 1000);
+        let mut tiff = TiffWriter::new(true);
+        let exif = interchange(&mut tiff, &preview, 0);
+        let start = tiff.blob(&thumbnail);
+        let main = tiff.ifd(
+            &[
+                (TAG_JPEG_OFFSET, LONG, 1, start),
+                (TAG_JPEG_LENGTH, LONG, 1, thumbnail.len() as u32),
+                (TAG_EXIF_IFD, LONG, 1, exif),
+            ],
+            0,
+        );
+        let data = tiff.first(main);
+        assert_eq!(embedded_preview(&data, ImageType::Arw), Some(&preview[..]));
+    }
+
+    #[test]
+    fn loops_and_bogus_counts_end() {
+        let preview = jpeg(0xc0, 300);
+        let mut tiff = TiffWriter::new(true);
+        let start = tiff.blob(&preview);
+        // The directory lists itself as its own successor and claims billions of sub-directories
+        let looped = tiff.data.len() as u32;
+        tiff.ifd(
+            &[
+                (TAG_JPEG_OFFSET, LONG, 1, start),
+                (TAG_JPEG_LENGTH, LONG, 1, preview.len() as u32),
+                (TAG_SUB_IFDS, LONG, u32::MAX, looped),
+            ],
+            looped,
+        );
+        let data = tiff.first(looped);
+        assert_eq!(embedded_preview(&data, ImageType::Nef), Some(&preview[..]));
+    }
+
+    #[test]
+    fn truncated_files_do_not_panic() {
+        let preview = jpeg(0xc0, 300);
+        let mut tiff = TiffWriter::new(true);
+        let sub = interchange(&mut tiff, &preview, 0);
+        let main = tiff.ifd(&[(TAG_SUB_IFDS, IFD, 1, sub)], 0);
+        let data = tiff.first(main);
+        for end in 0..data.len() {
+            let _ = embedded_preview(&data[..end], ImageType::Nef);
+        }
+    }
+
+    #[test]
+    fn raf_preview_comes_from_the_header() {
+        let preview = jpeg(0xc0, 300);
+        let mut data = b"FUJIFILMCCD-RAW 0201FF383501".to_vec();
+        data.resize(100, 0);
+        data[84..88].copy_from_slice(&100u32.to_be_bytes());
+        data[88..92].copy_from_slice(&(preview.len() as u32).to_be_bytes());
+        data.extend(&preview);
+        assert_eq!(embedded_preview(&data, ImageType::Raf), Some(&preview[..]));
+        assert_eq!(embedded_preview(&data[..200], ImageType::Raf), None);
+    }
+
+    #[test]
+    fn cr3_preview_follows_its_tag() {
+        let preview = jpeg(0xc0, 300);
+        let mut data = vec![0, 0, 0, 24];
+        data.extend(b"ftypcrx \0\0\0\x01crx isom");
+        data.extend(b"\0\0\0\0PRVW");
+        data.extend([0, 0, 0, 0, 0, 1, 0, 160, 0, 120, 0, 1]);
+        data.extend((preview.len() as u32).to_be_bytes());
+        data.extend(&preview);
+        assert_eq!(embedded_preview(&data, ImageType::Cr3), Some(&preview[..]));
+        assert_eq!(embedded_preview(&data[..data.len() - 1], ImageType::Cr3), None);
+    }
+}
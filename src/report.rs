@@ -0,0 +1,460 @@
+//! JSON, CSV and TSV reports of what a scan found.
+
+use std::borrow::Cow;
+use std::io::{self, Write};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use rayon::prelude::*;
+use serde::{Deserialize, Serialize};
+
+use crate::decode::image_dimensions;
+use crate::escape::escape_path;
+use crate::scan::{HashMode, ScanReport};
+
+/// Identifies the document as a dupchecker report to whatever reads it
+pub const REPORT_FORMAT: &str = "dupchecker-report";
+/// Bumped whenever a field is removed, renamed or changes meaning. New fields may be added
+/// without a bump, so readers should ignore fields they don't know.
+/// Version 2 writes paths escaped by [`escape_path`], so names that aren't UTF-8 survive.
+pub const REPORT_VERSION: u32 = 2;
+
+/// Everything a scan found, in the shape the JSON report is written in
+#[derive(Serialize, Deserialize)]
+pub struct Report {
+    /// Always [`REPORT_FORMAT`]
+    pub format: String,
+    /// [`REPORT_VERSION`] at the time of writing
+    pub version: u32,
+    /// When the report was written, as an RFC 3339 UTC timestamp
+    pub generated: String,
+    /// "exact" or "perceptual"
+    pub mode: String,
+    /// The name of the hash groups are keyed by, e.g. "md5" or "phash"
+    pub algorithm: String,
+    /// Maximum Hamming distance, perceptual mode only
+    pub threshold: Option<u32>,
+    /// The scanned folders, escaped like file paths
+    pub roots: Vec<String>,
+    /// Read-only folders the roots were checked against
+    #[serde(default)]
+    pub references: Vec<String>,
+    /// The keep rules in `--keep` syntax, or "first path" without any
+    pub keep_policy: String,
+    /// How much the scan looked at and found
+    pub stats: ReportStats,
+    /// Biggest savings first
+    pub groups: Vec<ReportGroup>,
+    /// Files and folders the scan had to skip
+    pub errors: Vec<ReportError>,
+    /// Images whose extension doesn't match their contents, only looked for with content detection
+    #[serde(default)]
+    pub mismatches: Vec<ReportMismatch>,
+}
+
+/// Totals over the whole scan
+#[derive(Serialize, Deserialize)]
+pub struct ReportStats {
+    /// Image files found under the roots
+    pub files_scanned: usize,
+    /// Total size of those files
+    pub bytes_scanned: u64,
+    /// Hashes worked out by reading files
+    pub hashes_computed: usize,
+    /// Hashes answered from the cache instead
+    pub hashes_cached: usize,
+    /// Duplicate groups found
+    pub groups: usize,
+    /// Files that would be removed, every member of a group but the one kept
+    pub duplicate_files: usize,
+    /// Their total size
+    pub reclaimable_bytes: u64,
+    /// Files and folders skipped
+    pub errors: usize,
+    /// How long the scan took, not counting writing the report
+    pub elapsed_seconds: f64,
+}
+
+/// One group of duplicates. The file to keep comes first.
+#[derive(Serialize, Deserialize)]
+pub struct ReportGroup {
+    /// Numbered from 1 in the order groups appear in the report
+    pub id: usize,
+    /// The hash the files share, tagged with its algorithm, e.g. `md5:900150983cd24fb0d6963f7d28e17f72`
+    pub hash: String,
+    /// The algorithm part of the hash
+    pub algorithm: String,
+    /// Size of the kept file. Exact duplicates all share it, perceptual ones may not.
+    pub size: u64,
+    /// Total size of every file but the one kept
+    pub reclaimable_bytes: u64,
+    /// Never fewer than two
+    pub files: Vec<ReportFile>,
+}
+
+/// One file of a group
+#[derive(Serialize, Deserialize)]
+pub struct ReportFile {
+    /// Escaped by [`escape_path`]: backslashes doubled, control characters and bytes that
+    /// aren't UTF-8 written as \t, \n, \r or \xHH
+    pub path: String,
+    /// Size in bytes
+    pub size: u64,
+    /// RFC 3339 UTC timestamp with nanoseconds, null when the filesystem doesn't record one
+    pub modified: Option<String>,
+    /// Pixel width, null when the image can't be decoded
+    pub width: Option<u32>,
+    /// Pixel height, null when the image can't be decoded
+    pub height: Option<u32>,
+    /// Whether this is the file the keep policy chose. Edit it to change which file `apply` keeps.
+    pub keep: bool,
+    /// Whether the file lies in a reference folder
+    #[serde(default)]
+    pub reference: bool,
+}
+
+/// A file or folder the scan had to skip
+#[derive(Serialize, Deserialize)]
+pub struct ReportError {
+    /// Escaped like the paths of files in groups
+    pub path: String,
+    /// "walk", "read" or "decode"
+    pub kind: String,
+    /// What went wrong
+    pub message: String,
+}
+
+/// An image whose extension doesn't match its contents
+#[derive(Serialize, Deserialize)]
+pub struct ReportMismatch {
+    /// Escaped like the paths of files in groups
+    pub path: String,
+    /// The type the contents say the file is, e.g. "PNG"
+    pub detected: String,
+    /// The extension a file of that type would usually have, e.g. "png"
+    pub extension: String,
+}
+
+// Year, month and day of a count of days since 1970-01-01, in the proleptic Gregorian calendar
+fn civil_from_days(days: i64) -> (i64, u32, u32) {
+    let days = days + 719_468;
+    let era = days.div_euclid(146_097);
+    let day_of_era = days.rem_euclid(146_097);
+    let year_of_era = (day_of_era - day_of_era / 1460 + day_of_era / 36_524 - day_of_era / 146_096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let month_index = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * month_index + 2) / 5 + 1;
+    let month = if month_index < 10 { month_index + 3 } else { month_index - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month as u32, day as u32)
+}
+
+// Days since 1970-01-01 of a date in the proleptic Gregorian calendar, the inverse of civil_from_days
+fn days_from_civil(year: i64, month: u32, day: u32) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year.rem_euclid(400);
+    let month_index = i64::from(if month > 2 { month - 3 } else { month + 9 });
+    let day_of_year = (153 * month_index + 2) / 5 + day as i64 - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+/// A time as an RFC 3339 UTC timestamp with nanoseconds, e.g. 2024-05-01T12:00:00.000000000Z.
+/// Times before 1970 aren't expected on image files and give None.
+pub fn format_timestamp(time: SystemTime) -> Option<String> {
+    let since_epoch = time.duration_since(UNIX_EPOCH).ok()?;
+    let seconds = since_epoch.as_secs();
+    let (year, month, day) = civil_from_days((seconds / 86_400) as i64);
+    let second_of_day = seconds % 86_400;
+    Some(format!(
+        "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}.{:09}Z",
+        year,
+        month,
+        day,
+        second_of_day / 3600,
+        second_of_day / 60 % 60,
+        second_of_day % 60,
+        since_epoch.subsec_nanos()
+    ))
+}
+
+/// Read back a timestamp written by format_timestamp. The fraction may have up to nine digits or be left out.
+pub fn parse_timestamp(timestamp: &str) -> Option<SystemTime> {
+    let (date, time) = timestamp.strip_suffix('Z')?.split_once('T')?;
+    let mut date_parts = date.splitn(3, '-');
+    let year: i64 = date_parts.next()?.parse().ok()?;
+    let month: u32 = date_parts.next()?.parse().ok()?;
+    let day: u32 = date_parts.next()?.parse().ok()?;
+
+    let (clock, fraction) = time.split_once('.').unwrap_or((time, ""));
+    let mut clock_parts = clock.splitn(3, ':');
+    let hour: u64 = clock_parts.next()?.parse().ok()?;
+    let minute: u64 = clock_parts.next()?.parse().ok()?;
+    let second: u64 = clock_parts.next()?.parse().ok()?;
+    if !(1..=12).contains(&month) || !(1..=31).contains(&day) || hour > 23 || minute > 59 || second > 59 {
+        return None;
+    }
+    if fraction.len() > 9 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let nanos: u32 = format!("{:0<9}", fraction).parse().ok()?;
+
+    let days = u64::try_from(days_from_civil(year, month, day)).ok()?;
+    let seconds = days * 86_400 + hour * 3600 + minute * 60 + second;
+    Some(UNIX_EPOCH + Duration::new(seconds, nanos))
+}
+
+impl Report {
+    /// Gather a scan's results into a report. Decodes every file in a group to measure it.
+    pub fn new(scan: &ScanReport) -> Report {
+        let (mode, threshold) = match scan.mode {
+            HashMode::Exact { .. } => ("exact", None),
+            HashMode::Perceptual { threshold, .. } => ("perceptual", Some(threshold)),
+        };
+        let algorithm = scan.mode.algorithm_name();
+
+        let groups: Vec<ReportGroup> = scan
+            .groups
+            .iter()
+            .enumerate()
+            .map(|(index, group)| {
+                let files: Vec<ReportFile> = group
+                    .files
+                    .par_iter()
+                    .enumerate()
+                    .map(|(position, file)| {
+                        let dimensions = image_dimensions(&file.path).ok();
+                        ReportFile {
+                            path: escape_path(&file.path),
+                            size: file.stamp.size,
+                            modified: file.stamp.modified.and_then(format_timestamp),
+                            width: dimensions.map(|(width, _)| width),
+                            height: dimensions.map(|(_, height)| height),
+                            keep: position == 0,
+                            reference: file.reference,
+                        }
+                    })
+                    .collect();
+                ReportGroup {
+                    id: index + 1,
+                    hash: group.hash.clone(),
+                    algorithm: algorithm.to_string(),
+                    size: files[0].size,
+                    reclaimable_bytes: files.iter().skip(1).map(|file| file.size).sum(),
+                    files,
+                }
+            })
+            .collect();
+
+        let stats = ReportStats {
+            files_scanned: scan.stats.files_found,
+            bytes_scanned: scan.stats.bytes_found,
+            hashes_computed: scan.stats.hashes_computed,
+            hashes_cached: scan.stats.hashes_cached,
+            groups: groups.len(),
+            duplicate_files: groups.iter().map(|group| group.files.len() - 1).sum(),
+            reclaimable_bytes: groups.iter().map(|group| group.reclaimable_bytes).sum(),
+            errors: scan.errors.len(),
+            elapsed_seconds: scan.stats.elapsed.as_secs_f64(),
+        };
+        let errors = scan
+            .errors
+            .iter()
+            .map(|error| ReportError {
+                path: escape_path(&error.path),
+                kind: error.kind.to_string(),
+                message: error.message.clone(),
+            })
+            .collect();
+        let mismatches = scan
+            .mismatches
+            .iter()
+            .map(|mismatch| ReportMismatch {
+                path: escape_path(&mismatch.path),
+                detected: mismatch.detected.to_string(),
+                extension: mismatch.detected.extensions()[0].to_string(),
+            })
+            .collect();
+
+        Report {
+            format: REPORT_FORMAT.to_string(),
+            version: REPORT_VERSION,
+            generated: format_timestamp(SystemTime::now()).unwrap_or_default(),
+            mode: mode.to_string(),
+            algorithm: algorithm.to_string(),
+            threshold,
+            roots: scan.roots.iter().map(|root| escape_path(root)).collect(),
+            references: scan.references.iter().map(|root| escape_path(root)).collect(),
+            keep_policy: scan.keep_policy.to_string(),
+            stats,
+            groups,
+            errors,
+            mismatches,
+        }
+    }
+
+    /// Write the report as pretty-printed JSON, ending with a newline
+    pub fn write_json(&self, out: &mut dyn Write) -> io::Result<()> {
+        serde_json::to_writer_pretty(&mut *out, self)?;
+        writeln!(out)
+    }
+}
+
+// Quote a CSV or TSV field when it holds the delimiter, a quote or a line break, doubling any
+// quotes inside it (RFC 4180). Spreadsheets read tab-separated files with the same rule.
+fn quote_field(field: &str, delimiter: char) -> Cow<'_, str> {
+    if field.contains([delimiter, '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+/// Write one row per file in a duplicate group, for spreadsheets: comma-separated for CSV,
+/// tab-separated for TSV. Groups are numbered the same way as in the JSON report.
+pub fn write_delimited(scan: &ScanReport, delimiter: char, out: &mut dyn Write) -> io::Result<()> {
+    let header = ["group", "hash", "size", "path", "modified", "action", "reference"];
+    writeln!(out, "{}", header.join(&delimiter.to_string()))?;
+
+    for (index, group) in scan.groups.iter().enumerate() {
+        for (position, file) in group.files.iter().enumerate() {
+            let row = [
+                (index + 1).to_string(),
+                group.hash.clone(),
+                file.stamp.size.to_string(),
+                escape_path(&file.path),
+                file.stamp.modified.and_then(format_timestamp).unwrap_or_default(),
+                // The keep policy has already moved the file to keep to the front
+                if position == 0 { "keep" } else { "remove" }.to_string(),
+                // Files in a reference folder are never removed, whatever the action column says
+                file.reference.to_string(),
+            ];
+            let fields: Vec<Cow<str>> = row.iter().map(|field| quote_field(field, delimiter)).collect();
+            writeln!(out, "{}", fields.join(&delimiter.to_string()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Split CSV or TSV text into rows of fields, undoing the quoting quote_field applies.
+/// Both \n and \r\n end a row.
+pub fn parse_delimited(text: &str, delimiter: char) -> Result<Vec<Vec<String>>, String> {
+    let mut rows = Vec::new();
+    let mut row = Vec::new();
+    let mut field = String::new();
+    let mut in_quotes = false;
+    let mut chars = text.chars().peekable();
+    while let Some(c) = chars.next() {
+        if in_quotes {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => in_quotes = false,
+                c => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.is_empty() => in_quotes = true,
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                row.push(std::mem::take(&mut field));
+                rows.push(std::mem::take(&mut row));
+            }
+            c if c == delimiter => row.push(std::mem::take(&mut field)),
+            c => field.push(c),
+        }
+    }
+    if in_quotes {
+        return Err(format!("unterminated quoted field in row {}", rows.len() + 1));
+    }
+    if !field.is_empty() || !row.is_empty() {
+        row.push(field);
+        rows.push(row);
+    }
+    Ok(rows)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn quoted_fields_round_trip() {
+        let rows = vec![
+            vec!["plain".to_string(), "with,comma".to_string(), "with \"quotes\"".to_string()],
+            vec!["line\nbreak".to_string(), "crlf\r\ninside".to_string(), String::new()],
+            vec!["tab\there".to_string(), "\"".to_string(), ",".to_string()],
+        ];
+        for delimiter in [',', '\t'] {
+            let text: String = rows
+                .iter()
+                .map(|row| {
+                    let fields: Vec<Cow<str>> = row.iter().map(|field| quote_field(field, delimiter)).collect();
+                    fields.join(&delimiter.to_string()) + "\n"
+                })
+                .collect();
+            assert_eq!(parse_delimited(&text, delimiter).unwrap(), rows, "delimiter {:?}", delimiter);
+        }
+    }
+
+    #[test]
+    fn fields_are_only_quoted_when_needed() {
+        assert_eq!(quote_field("photos/a.jpg", ','), "photos/a.jpg");
+        assert_eq!(quote_field("a,b.jpg", ','), "\"a,b.jpg\"");
+        assert_eq!(quote_field("a,b.jpg", '\t'), "a,b.jpg");
+        assert_eq!(quote_field("say \"cheese\".jpg", '\t'), "\"say \"\"cheese\"\".jpg\"");
+    }
+
+    #[test]
+    fn crlf_and_a_missing_final_newline_are_accepted() {
+        let rows = parse_delimited("a,b\r\nc,d", ',').unwrap();
+        assert_eq!(rows, vec![vec!["a", "b"], vec!["c", "d"]]);
+    }
+
+    #[test]
+    fn unterminated_quotes_are_rejected() {
+        assert!(parse_delimited("a,\"b\nc,d\n", ',').is_err());
+    }
+
+    #[test]
+    fn timestamps_round_trip() {
+        for (seconds, nanos) in [(0, 0), (951_782_400, 1), (1_709_164_800, 999_999_999), (4_102_444_799, 500)] {
+            let time = UNIX_EPOCH + Duration::new(seconds, nanos);
+            let formatted = format_timestamp(time).unwrap();
+            assert_eq!(parse_timestamp(&formatted), Some(time), "{}", formatted);
+        }
+    }
+
+    #[test]
+    fn timestamps_are_rfc_3339() {
+        let leap_day = UNIX_EPOCH + Duration::new(1_709_210_096, 7);
+        assert_eq!(format_timestamp(leap_day).unwrap(), "2024-02-29T12:34:56.000000007Z");
+        assert_eq!(format_timestamp(UNIX_EPOCH - Duration::from_secs(1)), None);
+    }
+
+    #[test]
+    fn timestamps_may_have_shorter_fractions() {
+        let expected = UNIX_EPOCH + Duration::new(1_709_210_096, 500_000_000);
+        assert_eq!(parse_timestamp("2024-02-29T12:34:56.5Z"), Some(expected));
+        assert_eq!(parse_timestamp("2024-02-29T12:34:56Z"), Some(expected - Duration::from_millis(500)));
+    }
+
+    #[test]
+    fn malformed_timestamps_are_rejected() {
+        for timestamp in [
+            "",
+            "2024-02-29 12:34:56Z",
+            "2024-02-29T12:34:56",
+            "2024-13-01T00:00:00Z",
+            "2024-02-29T24:00:00Z",
+            "2024-02-29T12:34:56.1234567890Z",
+            "2024-02-29T12:34:56.5xZ",
+            "1969-12-31T23:59:59Z",
+        ] {
+            assert_eq!(parse_timestamp(timestamp), None, "{} was accepted", timestamp);
+        }
+    }
+}
@@ -0,0 +1,919 @@
+//! Walking the roots, hashing files and grouping duplicates, and the types a scan returns.
+
+use std::collections::{HashMap, HashSet};
+use std::fmt;
+use std::fs::{self, File};
+use std::io::{ErrorKind, Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant, SystemTime};
+
+use rayon::prelude::*;
+use rayon::{ThreadPool, ThreadPoolBuilder};
+use walkdir::WalkDir;
+
+use crate::cache::HashCache;
+use crate::escape::escape_path;
+use crate::filetype::{Detection, ImageType};
+use crate::hasher::HashAlgorithm;
+use crate::keep::KeepPolicy;
+use crate::perceptual::{self, PerceptualAlgorithm};
+
+/// The extensions scanned when none are given: every extension of every type a scan recognises
+pub fn default_extensions() -> Vec<String> {
+    ImageType::ALL.iter().flat_map(ImageType::extensions).map(|extension| extension.to_string()).collect()
+}
+
+/// How images are compared: exact file contents, or perceptual similarity
+#[derive(Clone, Copy, Debug)]
+pub enum HashMode {
+    /// Files whose whole contents hash the same
+    Exact {
+        /// The content hash to compare with
+        algorithm: HashAlgorithm,
+    },
+    /// Images whose perceptual hashes are close
+    Perceptual {
+        /// The perceptual hash to compare with
+        algorithm: PerceptualAlgorithm,
+        /// The most bits, out of 64, that two hashes may differ in
+        threshold: u32,
+    },
+}
+
+impl HashMode {
+    /// The name of the hash the groups are keyed by
+    pub fn algorithm_name(&self) -> &'static str {
+        match self {
+            HashMode::Exact { algorithm } => algorithm.name(),
+            HashMode::Perceptual { algorithm, .. } => algorithm.name(),
+        }
+    }
+}
+
+// Everything that controls which files a scan picks up and how it compares them.
+// Set through the Scanner builder.
+pub(crate) struct ScanOptions {
+    // Lowercase extensions to scan, and to leave out even when included
+    pub extensions: Vec<String>,
+    pub exclude: Vec<String>,
+    // Whether a file's extension or its first bytes say it is an image
+    pub detection: Detection,
+    pub mode: HashMode,
+    // Abort on the first unreadable file instead of skipping it
+    pub strict: bool,
+    // Number of files hashed at once, 0 for one per CPU core
+    pub jobs: usize,
+    // Where to keep hashes between runs, if anywhere
+    pub cache: Option<PathBuf>,
+    // Hash every file again instead of trusting the cache
+    pub rehash: bool,
+    // Which file in each group survives
+    pub keep: KeepPolicy,
+}
+
+/// Which stage of the scan a file failed in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ScanErrorKind {
+    /// A folder couldn't be listed
+    Walk,
+    /// A file couldn't be read
+    Read,
+    /// A file was read but isn't an image opencv can decode
+    Decode,
+}
+
+impl fmt::Display for ScanErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            ScanErrorKind::Walk => "walk",
+            ScanErrorKind::Read => "read",
+            ScanErrorKind::Decode => "decode",
+        };
+        f.write_str(name)
+    }
+}
+
+/// A file or directory the scan had to skip
+#[derive(Debug)]
+pub struct ScanError {
+    /// The file or folder
+    pub path: PathBuf,
+    /// Which stage it failed in
+    pub kind: ScanErrorKind,
+    /// What went wrong
+    pub message: String,
+}
+
+impl fmt::Display for ScanError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({} error): {}", escape_path(&self.path), self.kind, self.message)
+    }
+}
+
+/// An image whose extension doesn't match its contents, found when detecting types by content
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TypeMismatch {
+    /// The image
+    pub path: PathBuf,
+    /// What the contents say the file is
+    pub detected: ImageType,
+}
+
+impl fmt::Display for TypeMismatch {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.path.extension() {
+            Some(extension) => write!(
+                f,
+                "{} is named .{} but holds a {} image",
+                escape_path(&self.path),
+                escape_path(Path::new(extension)),
+                self.detected
+            ),
+            None => write!(f, "{} has no extension but holds a {} image", escape_path(&self.path), self.detected),
+        }
+    }
+}
+
+/// What a file looked like when it was hashed, so later steps can tell if it has changed since
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct FileStamp {
+    /// Size in bytes
+    pub size: u64,
+    /// None when the filesystem doesn't record modification times
+    pub modified: Option<SystemTime>,
+    /// Tells a file apart from a different one later saved under the same name. Always 0 on
+    /// systems other than Unix.
+    pub inode: u64,
+    /// The device the file is on. Together with the inode it tells hard links to one file apart
+    /// from copies of it. Always 0 on systems other than Unix.
+    pub dev: u64,
+}
+
+impl FileStamp {
+    /// The stamp of a file whose metadata has already been read
+    pub fn from_metadata(metadata: &fs::Metadata) -> FileStamp {
+        #[cfg(unix)]
+        let (dev, inode) = {
+            use std::os::unix::fs::MetadataExt;
+            (metadata.dev(), metadata.ino())
+        };
+        #[cfg(not(unix))]
+        let (dev, inode) = (0, 0);
+
+        FileStamp { size: metadata.len(), modified: metadata.modified().ok(), inode, dev }
+    }
+
+    /// The device and inode, which are the same for every hard link to a file and differ
+    /// between any two other files. None where the system doesn't have them.
+    pub fn file_id(&self) -> Option<(u64, u64)> {
+        cfg!(unix).then_some((self.dev, self.inode))
+    }
+
+    /// Read a file's metadata, following symlinks, and stamp it
+    pub fn of(path: &Path) -> std::io::Result<FileStamp> {
+        Ok(FileStamp::from_metadata(&fs::metadata(path)?))
+    }
+}
+
+/// How much work a scan did
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ScanStats {
+    /// Image files found under the roots
+    pub files_found: usize,
+    /// Total size of those files
+    pub bytes_found: u64,
+    /// Hashes worked out by reading files. Exact scans can hash a file twice,
+    /// once partially and once in full.
+    pub hashes_computed: usize,
+    /// Hashes answered from the cache instead
+    pub hashes_cached: usize,
+    /// How long the scan took
+    pub elapsed: Duration,
+}
+
+/// One file of a duplicate group
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileEntry {
+    /// Where the file is, as found under its root
+    pub path: PathBuf,
+    /// How the file looked when it was hashed
+    pub stamp: FileStamp,
+    /// Whether the file lies in a reference root, which is never touched
+    pub reference: bool,
+}
+
+/// Files that hold the same image
+#[derive(Clone, Debug)]
+pub struct DuplicateGroup {
+    /// The hash the files share, tagged with its algorithm, e.g. `md5:900150983cd24fb0d6963f7d28e17f72`.
+    /// Perceptual groups are keyed by their first member's hash, which the others only come close to.
+    pub hash: String,
+    /// Never fewer than two. The file to keep comes first and the rest are sorted by path.
+    /// When scanning against reference roots, the file to keep is a reference copy and
+    /// every other file is a copy in a target root.
+    pub files: Vec<FileEntry>,
+}
+
+impl DuplicateGroup {
+    /// The file the keep policy chose to survive
+    pub fn keep(&self) -> &FileEntry {
+        &self.files[0]
+    }
+
+    /// Every file but the one kept
+    pub fn duplicates(&self) -> &[FileEntry] {
+        &self.files[1..]
+    }
+
+    /// Space freed by removing the duplicates
+    pub fn reclaimable_bytes(&self) -> u64 {
+        self.duplicates().iter().map(|file| file.stamp.size).sum()
+    }
+}
+
+/// Everything a scan found, and what it was asked to look for
+#[derive(Debug)]
+pub struct ScanReport {
+    /// The folders searched for duplicates to remove
+    pub roots: Vec<PathBuf>,
+    /// Read-only folders the roots are checked against
+    pub references: Vec<PathBuf>,
+    /// How files were compared
+    pub mode: HashMode,
+    /// How the file kept in each group was chosen
+    pub keep_policy: KeepPolicy,
+    /// The groups that free the most space come first, ties broken by hash
+    pub groups: Vec<DuplicateGroup>,
+    /// Files and directories that had to be skipped, sorted by path
+    pub errors: Vec<ScanError>,
+    /// Images whose extension doesn't match their contents, sorted by path.
+    /// Only looked for when detecting types by content.
+    pub mismatches: Vec<TypeMismatch>,
+    /// Problems that didn't stop the scan, such as a hash cache that couldn't be used
+    pub warnings: Vec<String>,
+    /// How much work the scan did
+    pub stats: ScanStats,
+}
+
+// What a scan has found so far
+#[derive(Default)]
+struct ScanResults {
+    // Groups keyed by the hash their members share
+    duplicates: Vec<(String, Vec<PathBuf>)>,
+    errors: Vec<ScanError>,
+    mismatches: Vec<TypeMismatch>,
+}
+
+impl ScanResults {
+    // Remember a skipped file, or give up straight away in strict mode
+    fn skip(&mut self, error: ScanError, strict: bool) -> Result<(), Box<dyn std::error::Error>> {
+        if strict {
+            return Err(error.to_string().into());
+        }
+        self.errors.push(error);
+        Ok(())
+    }
+}
+
+// Size of the buffer files are streamed through while hashing
+const HASH_BUFFER_BYTES: usize = 64 * 1024;
+
+/// Hash a file's entire contents, as a lowercase hexadecimal string
+pub fn calculate_image_hash(image_path: &Path, algorithm: HashAlgorithm) -> Result<String, Box<dyn std::error::Error>> {
+    // Open the image file
+    let mut file = File::open(image_path)?;
+    let mut buffer = vec![0u8; HASH_BUFFER_BYTES];
+
+    // Feed the file's contents to the hasher one buffer at a time,
+    // so memory use doesn't grow with the size of the file
+    let mut hasher = algorithm.hasher();
+    loop {
+        let read = match file.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        hasher.update(&buffer[..read]);
+    }
+
+    // Return the hash as a hexadecimal string
+    Ok(hasher.finish())
+}
+
+// How much of each end of a file the partial hash looks at
+const PARTIAL_HASH_BYTES: u64 = 4096;
+
+// Hash only the first and last few KiB of a file, enough to tell most same-size files apart
+fn calculate_partial_hash(image_path: &Path, size: u64, algorithm: HashAlgorithm) -> Result<String, Box<dyn std::error::Error>> {
+    let mut file = File::open(image_path)?;
+    let mut hasher = algorithm.hasher();
+    let mut buffer = vec![0u8; PARTIAL_HASH_BYTES as usize];
+
+    // Small files are covered entirely by the head
+    let head = size.min(PARTIAL_HASH_BYTES) as usize;
+    file.read_exact(&mut buffer[..head])?;
+    hasher.update(&buffer[..head]);
+
+    // Skip ahead to the tail, without re-reading bytes the head already covered
+    if size > PARTIAL_HASH_BYTES {
+        let tail_start = (size - PARTIAL_HASH_BYTES).max(PARTIAL_HASH_BYTES);
+        let tail = (size - tail_start) as usize;
+        file.seek(SeekFrom::Start(tail_start))?;
+        file.read_exact(&mut buffer[..tail])?;
+        hasher.update(&buffer[..tail]);
+    }
+
+    Ok(hasher.finish())
+}
+
+// Run `hash` over every path on the pool, keeping results in the same order as the input
+// so a parallel scan groups files exactly like a sequential one would.
+// With `strict`, work stops at the first failure and the results end with the failure that
+// comes first in input order, so the same error aborts a strict scan every time.
+fn hash_all<T: Send>(
+    pool: &ThreadPool,
+    image_paths: Vec<PathBuf>,
+    strict: bool,
+    hash: impl Fn(&Path) -> Result<T, Box<dyn std::error::Error>> + Sync,
+) -> Vec<(PathBuf, Result<T, String>)> {
+    // Files after the earliest failure so far are skipped, files before it are all hashed
+    let first_failure = AtomicUsize::new(usize::MAX);
+    let results: Vec<(PathBuf, Option<Result<T, String>>)> = pool.install(|| {
+        image_paths
+            .into_par_iter()
+            .enumerate()
+            .map(|(index, image_path)| {
+                if strict && index > first_failure.load(Ordering::Relaxed) {
+                    return (image_path, None);
+                }
+                let result = hash(&image_path).map_err(|e| e.to_string());
+                if strict && result.is_err() {
+                    first_failure.fetch_min(index, Ordering::Relaxed);
+                }
+                (image_path, Some(result))
+            })
+            .collect()
+    });
+    let end = first_failure.into_inner().saturating_add(1).min(results.len());
+    results.into_iter().take(end).map(|(image_path, result)| (image_path, result.expect("hashed"))).collect()
+}
+
+// Whether a file's extension is on the include list and not on the exclude list
+fn extension_wanted(path: &Path, options: &ScanOptions) -> bool {
+    let Some(extension) = path.extension() else {
+        return false;
+    };
+    let extension = extension.to_str().unwrap_or("").to_lowercase();
+    options.extensions.contains(&extension) && !options.exclude.contains(&extension)
+}
+
+// Read the start of every file to keep only the images of the wanted types, whatever their
+// names say, noting each image whose extension says otherwise
+fn detect_images(
+    pool: &ThreadPool,
+    paths: Vec<PathBuf>,
+    options: &ScanOptions,
+    results: &mut ScanResults,
+) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+    let detect = |path: &Path| -> Result<Option<ImageType>, Box<dyn std::error::Error>> { Ok(ImageType::detect(path)?) };
+    let mut image_paths = Vec::new();
+    for (path, detected) in hash_all(pool, paths, options.strict, detect) {
+        let image_type = match detected {
+            Ok(Some(image_type)) => image_type,
+            // Not an image, or not one we know
+            Ok(None) => continue,
+            Err(message) => {
+                results.skip(ScanError { path, kind: ScanErrorKind::Read, message }, options.strict)?;
+                continue;
+            }
+        };
+        if !image_type.listed_in(&options.extensions) || image_type.listed_in(&options.exclude) {
+            continue;
+        }
+        let extension = path.extension().and_then(|extension| extension.to_str()).unwrap_or("").to_lowercase();
+        if ImageType::from_extension(&extension) != Some(image_type) {
+            results.mismatches.push(TypeMismatch { path: path.clone(), detected: image_type });
+        }
+        image_paths.push(path);
+    }
+    Ok(image_paths)
+}
+
+// Everything a scan hashes files with: the worker pool and, when enabled, the hash cache
+struct Hashing {
+    pool: ThreadPool,
+    cache: Option<HashCache>,
+    // Ignore cached hashes, but still store the fresh ones
+    rehash: bool,
+    // Stop at the first file that can't be hashed, before caching anything
+    strict: bool,
+    hashes_computed: usize,
+    hashes_cached: usize,
+    warnings: Vec<String>,
+}
+
+impl Hashing {
+    // Give up on the cache after an error rather than failing the whole scan
+    fn drop_cache(&mut self, e: rusqlite::Error) {
+        self.warnings.push(format!("hash cache disabled: {}", e));
+        self.cache = None;
+    }
+
+    // Hash every path, answering from the cache for files that haven't changed since they were
+    // last hashed with the same `kind`. Results come back in the same order as `image_paths`,
+    // and in strict mode end at the first failure.
+    fn run(
+        &mut self,
+        kind: &str,
+        image_paths: Vec<PathBuf>,
+        stamps: &HashMap<PathBuf, FileStamp>,
+        hash: impl Fn(&Path) -> Result<String, Box<dyn std::error::Error>> + Sync,
+    ) -> Vec<(PathBuf, Result<String, String>)> {
+        // Look everything up first, the cache connection can't be shared with the workers
+        let mut cached: Vec<Option<String>> = vec![None; image_paths.len()];
+        if let Some(cache) = &self.cache
+            && !self.rehash
+        {
+            for (i, image_path) in image_paths.iter().enumerate() {
+                match cache.get(image_path, &stamps[image_path], kind) {
+                    Ok(hash) => cached[i] = hash,
+                    Err(e) => {
+                        self.drop_cache(e);
+                        break;
+                    }
+                }
+            }
+        }
+
+        let uncached: Vec<PathBuf> = image_paths
+            .iter()
+            .zip(&cached)
+            .filter(|(_, hash)| hash.is_none())
+            .map(|(image_path, _)| image_path.clone())
+            .collect();
+        self.hashes_cached += image_paths.len() - uncached.len();
+        self.hashes_computed += uncached.len();
+        let fresh = hash_all(&self.pool, uncached, self.strict, hash);
+
+        // Remember the new hashes for next time, unless a strict scan is about to give up
+        let failed = self.strict && fresh.iter().any(|(_, hash)| hash.is_err());
+        if let Some(cache) = &mut self.cache
+            && !failed
+        {
+            let entries: Vec<(&Path, &FileStamp, &str)> = fresh
+                .iter()
+                .filter_map(|(image_path, hash)| {
+                    let hash = hash.as_ref().ok()?;
+                    Some((image_path.as_path(), &stamps[image_path], hash.as_str()))
+                })
+                .collect();
+            if let Err(e) = cache.put_all(kind, &entries) {
+                self.drop_cache(e);
+            }
+        }
+
+        // Merge cached and fresh hashes back into input order
+        let mut fresh = fresh.into_iter();
+        image_paths
+            .into_iter()
+            .zip(cached)
+            .map_while(|(image_path, hash)| match hash {
+                Some(hash) => Some((image_path, Ok(hash))),
+                // Only runs out when a strict scan stopped early
+                None => fresh.next(),
+            })
+            .collect()
+    }
+}
+
+// Against reference roots, a group only matters when it has copies both in a reference root
+// and in a target root. One reference copy is kept and the others are left out of the group,
+// since nothing ever happens to them.
+fn keep_references(groups: Vec<DuplicateGroup>, keep: &KeepPolicy) -> Vec<DuplicateGroup> {
+    groups
+        .into_iter()
+        .filter_map(|group| {
+            let (references, targets): (Vec<FileEntry>, Vec<FileEntry>) =
+                group.files.into_iter().partition(|file| file.reference);
+            if references.is_empty() || targets.is_empty() {
+                return None;
+            }
+            let keeper = keep.choose(&references);
+            let mut files = vec![references.into_iter().nth(keeper).expect("the chosen file is in the group")];
+            files.extend(targets);
+            Some(DuplicateGroup { hash: group.hash, files })
+        })
+        .collect()
+}
+
+// Function to find the duplicate images under a set of folders, optionally only those
+// that duplicate something under a set of reference folders
+pub(crate) fn find_duplicate_images(
+    folder_paths: &[PathBuf],
+    references: &[PathBuf],
+    options: &ScanOptions,
+) -> Result<ScanReport, Box<dyn std::error::Error>> {
+    let started = Instant::now();
+    if folder_paths.is_empty() {
+        return Err("no folders to scan".into());
+    }
+
+    // Check if the folders exist
+    for folder_path in folder_paths.iter().chain(references) {
+        if !folder_path.is_dir() {
+            return Err(format!("Folder not found at {}", escape_path(folder_path)).into());
+        }
+    }
+
+    // Get a list of image paths in the folders and subfolders
+    let mut results = ScanResults::default();
+    let mut report = ScanReport {
+        roots: folder_paths.to_vec(),
+        references: references.to_vec(),
+        mode: options.mode,
+        keep_policy: options.keep.clone(),
+        groups: Vec::new(),
+        errors: Vec::new(),
+        mismatches: Vec::new(),
+        warnings: Vec::new(),
+        stats: ScanStats::default(),
+    };
+    let pool = ThreadPoolBuilder::new().num_threads(options.jobs).build()?;
+    let mut image_paths: Vec<PathBuf> = Vec::new();
+    // With more than one root the same file can be reached twice, say through a folder and
+    // one inside it, and would then be grouped as a duplicate of itself. Files are told
+    // apart by their canonical paths, which also place them in a reference root or not.
+    let multiple_roots = folder_paths.len() + references.len() > 1;
+    let canonical_references: Vec<PathBuf> = references.iter().filter_map(|root| fs::canonicalize(root).ok()).collect();
+    let mut seen: HashSet<PathBuf> = HashSet::new();
+    let mut reference_paths: HashSet<PathBuf> = HashSet::new();
+    for folder_path in folder_paths.iter().chain(references) {
+        // Read directories in name order, so every scan finds files in the same order
+        for entry in WalkDir::new(folder_path).sort_by_file_name() {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) => {
+                    let path = e.path().unwrap_or(folder_path).to_path_buf();
+                    results.skip(ScanError { path, kind: ScanErrorKind::Walk, message: e.to_string() }, options.strict)?;
+                    continue;
+                }
+            };
+            let path = entry.path();
+            // Symlinks aren't followed, and hard links are collapsed further down, so links left by
+            // `delete --link` don't show up as duplicates again.
+            // When detecting by content every file is a candidate until its first bytes are read.
+            if entry.file_type().is_file()
+                && (options.detection == Detection::Content || extension_wanted(path, options))
+            {
+                if multiple_roots {
+                    let canonical = fs::canonicalize(path).unwrap_or_else(|_| path.to_path_buf());
+                    if canonical_references.iter().any(|root| canonical.starts_with(root)) {
+                        reference_paths.insert(path.to_path_buf());
+                    }
+                    if !seen.insert(canonical) {
+                        continue;
+                    }
+                }
+                image_paths.push(path.to_path_buf());
+            }
+        }
+    }
+    if options.detection == Detection::Content {
+        image_paths = detect_images(&pool, image_paths, options, &mut results)?;
+    }
+    results.mismatches.sort_by(|a, b| a.path.cmp(&b.path));
+    report.mismatches = std::mem::take(&mut results.mismatches);
+
+    if image_paths.is_empty() {
+        report.errors = results.errors;
+        return Ok(report); // Return empty results
+    }
+
+    // Note how every file looks before hashing it, both for the cache and to spot later changes.
+    // Hard links to one file are the same file under several names: removing one frees nothing,
+    // so only the first name found is scanned. It counts as a reference if any of the names is.
+    let mut stamps: HashMap<PathBuf, FileStamp> = HashMap::new();
+    let mut stamped_paths: Vec<PathBuf> = Vec::new();
+    let mut linked: HashMap<(u64, u64), PathBuf> = HashMap::new();
+    for image_path in image_paths {
+        match FileStamp::of(&image_path) {
+            Ok(stamp) => {
+                if let Some(first) = stamp.file_id().and_then(|id| linked.get(&id)) {
+                    if reference_paths.contains(&image_path) {
+                        reference_paths.insert(first.clone());
+                    }
+                    continue;
+                }
+                if let Some(id) = stamp.file_id() {
+                    linked.insert(id, image_path.clone());
+                }
+                stamps.insert(image_path.clone(), stamp);
+                stamped_paths.push(image_path);
+            }
+            Err(e) => {
+                let error = ScanError { path: image_path, kind: ScanErrorKind::Read, message: e.to_string() };
+                results.skip(error, options.strict)?;
+            }
+        }
+    }
+
+    let cache = match &options.cache {
+        Some(cache_path) => match HashCache::open(cache_path) {
+            Ok(cache) => Some(cache),
+            Err(e) => {
+                report.warnings.push(format!("not using hash cache {}: {}", escape_path(cache_path), e));
+                None
+            }
+        },
+        None => None,
+    };
+    let mut hashing = Hashing {
+        pool,
+        cache,
+        rehash: options.rehash,
+        strict: options.strict,
+        hashes_computed: 0,
+        hashes_cached: 0,
+        warnings: Vec::new(),
+    };
+    match options.mode {
+        HashMode::Exact { algorithm } => {
+            find_exact_duplicates(stamped_paths, &stamps, algorithm, options, &mut hashing, &mut results)?
+        }
+        HashMode::Perceptual { algorithm, threshold } => {
+            find_similar_images(stamped_paths, &stamps, algorithm, threshold, options, &mut hashing, &mut results)?
+        }
+    }
+
+    report.groups = results
+        .duplicates
+        .into_iter()
+        .map(|(hash, mut image_paths)| {
+            image_paths.sort();
+            let files = image_paths
+                .into_iter()
+                .map(|path| FileEntry { stamp: stamps[&path], reference: reference_paths.contains(&path), path })
+                .collect();
+            DuplicateGroup { hash, files }
+        })
+        .collect();
+    // Paths are sorted first, so the keep policy breaks its ties the same way every time
+    if references.is_empty() {
+        options.keep.apply(&mut report.groups);
+    } else {
+        report.groups = keep_references(std::mem::take(&mut report.groups), &options.keep);
+    }
+
+    // Biggest savings first, then by hash, so reports from successive scans can be diffed
+    report.groups.sort_by(|a, b| {
+        b.reclaimable_bytes().cmp(&a.reclaimable_bytes()).then_with(|| a.hash.cmp(&b.hash))
+    });
+    report.errors = results.errors;
+    report.errors.sort_by(|a, b| a.path.cmp(&b.path));
+    report.warnings.append(&mut hashing.warnings);
+
+    report.stats = ScanStats {
+        files_found: stamps.len(),
+        bytes_found: stamps.values().map(|stamp| stamp.size).sum(),
+        hashes_computed: hashing.hashes_computed,
+        hashes_cached: hashing.hashes_cached,
+        elapsed: started.elapsed(),
+    };
+    Ok(report)
+}
+
+// Group images that look alike, even if they were re-encoded, resized or stripped of metadata
+fn find_similar_images(
+    image_paths: Vec<PathBuf>,
+    stamps: &HashMap<PathBuf, FileStamp>,
+    algorithm: PerceptualAlgorithm,
+    threshold: u32,
+    options: &ScanOptions,
+    hashing: &mut Hashing,
+    results: &mut ScanResults,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Calculate the perceptual hash for each image, then cluster the ones that look alike
+    let mut hashed_paths: Vec<PathBuf> = Vec::new();
+    let mut hashes: Vec<u64> = Vec::new();
+    let hash_hex = |path: &Path| -> Result<String, Box<dyn std::error::Error>> {
+        Ok(format!("{:016x}", perceptual::perceptual_hash(path, algorithm)?))
+    };
+    for (image_path, hash) in hashing.run(&algorithm.cache_kind(), image_paths, stamps, hash_hex) {
+        match hash.and_then(|hex| u64::from_str_radix(&hex, 16).map_err(|e| e.to_string())) {
+            Ok(hash) => {
+                hashes.push(hash);
+                hashed_paths.push(image_path);
+            }
+            Err(message) => {
+                let error = ScanError { path: image_path, kind: ScanErrorKind::Decode, message };
+                results.skip(error, options.strict)?;
+            }
+        }
+    }
+
+    // Key each group by the hash of its first member, since members' hashes can differ
+    for members in perceptual::group_similar(&hashes, threshold) {
+        let key = format!("{}:{:016x}", algorithm.name(), hashes[members[0]]);
+        let paths = members.iter().map(|&i| hashed_paths[i].clone()).collect();
+        results.duplicates.push((key, paths));
+    }
+
+    Ok(())
+}
+
+// Group images whose file contents hash identically.
+// Works in stages so only files that could still be duplicates are read in full:
+// first by size, then by a hash of their head and tail, then by a hash of everything.
+fn find_exact_duplicates(
+    image_paths: Vec<PathBuf>,
+    stamps: &HashMap<PathBuf, FileStamp>,
+    algorithm: HashAlgorithm,
+    options: &ScanOptions,
+    hashing: &mut Hashing,
+    results: &mut ScanResults,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Files of different sizes can never be duplicates, and sizes are already known
+    let mut by_size: HashMap<u64, Vec<PathBuf>> = HashMap::new();
+    for image_path in image_paths {
+        by_size.entry(stamps[&image_path].size).or_default().push(image_path);
+    }
+
+    // Same-size files that already differ near the start or end can't be duplicates either.
+    // Candidates are sorted so they are hashed, and fail, in the same order every run.
+    let mut candidates: Vec<PathBuf> = by_size.into_values().filter(|paths| paths.len() > 1).flatten().collect();
+    candidates.sort();
+    let partial_kind = format!("{}-partial", algorithm.name());
+    let partial_hash = |path: &Path| calculate_partial_hash(path, stamps[path].size, algorithm);
+    let mut by_partial: HashMap<(u64, String), Vec<PathBuf>> = HashMap::new();
+    for (image_path, partial_hash) in hashing.run(&partial_kind, candidates, stamps, partial_hash) {
+        match partial_hash {
+            Ok(partial_hash) => by_partial.entry((stamps[&image_path].size, partial_hash)).or_default().push(image_path),
+            Err(message) => {
+                let error = ScanError { path: image_path, kind: ScanErrorKind::Read, message };
+                results.skip(error, options.strict)?;
+            }
+        }
+    }
+
+    // Calculate the full hash for each remaining image and store it in a HashMap
+    let mut candidates: Vec<PathBuf> = by_partial.into_values().filter(|paths| paths.len() > 1).flatten().collect();
+    candidates.sort();
+    // Tag each hash with its algorithm so results from different algorithms never mix
+    let mut image_hashes: HashMap<String, Vec<PathBuf>> = HashMap::new();
+    let full_hash = |path: &Path| calculate_image_hash(path, algorithm);
+    for (image_path, image_hash) in hashing.run(algorithm.name(), candidates, stamps, full_hash) {
+        match image_hash {
+            Ok(image_hash) => {
+                let key = format!("{}:{}", algorithm.name(), image_hash);
+                image_hashes.entry(key).or_default().push(image_path)
+            }
+            Err(message) => {
+                let error = ScanError { path: image_path, kind: ScanErrorKind::Read, message };
+                results.skip(error, options.strict)?;
+            }
+        }
+    }
+
+    // Filter out entries that are not duplicates
+    results.duplicates = image_hashes
+        .into_iter()
+        .filter(|(_, paths)| paths.len() > 1)
+        .collect();
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A file in the temporary folder, removed when dropped
+    struct TestFile(PathBuf);
+
+    impl TestFile {
+        fn new(name: &str, contents: &[u8]) -> TestFile {
+            let path = std::env::temp_dir().join(format!("dupchecker-{}-{}", std::process::id(), name));
+            fs::write(&path, contents).unwrap();
+            TestFile(path)
+        }
+    }
+
+    impl Drop for TestFile {
+        fn drop(&mut self) {
+            let _ = fs::remove_file(&self.0);
+        }
+    }
+
+    // Bytes that mostly differ from their neighbours, so shifted ranges hash differently
+    fn contents(size: usize) -> Vec<u8> {
+        (0..size).map(|i| (i * 31 % 251) as u8).collect()
+    }
+
+    fn md5_of(parts: &[&[u8]]) -> String {
+        let mut hasher = HashAlgorithm::Md5.hasher();
+        for part in parts {
+            hasher.update(part);
+        }
+        hasher.finish()
+    }
+
+    #[test]
+    fn full_hash_covers_the_whole_file() {
+        let file = TestFile::new("full", b"abc");
+        assert_eq!(calculate_image_hash(&file.0, HashAlgorithm::Md5).unwrap(), "900150983cd24fb0d6963f7d28e17f72");
+        let data = contents(3 * HASH_BUFFER_BYTES + 1);
+        let file = TestFile::new("full-large", &data);
+        assert_eq!(calculate_image_hash(&file.0, HashAlgorithm::Md5).unwrap(), md5_of(&[&data]));
+    }
+
+    #[test]
+    fn partial_hash_reads_head_and_tail_once() {
+        let block = PARTIAL_HASH_BYTES as usize;
+        for size in [0, 1, block - 1, block, block + 1, 2 * block - 1, 2 * block, 2 * block + 1, 100_000] {
+            let data = contents(size);
+            let file = TestFile::new(&format!("partial-{}", size), &data);
+            // The tail never starts inside the head, so no byte is hashed twice
+            let head = &data[..size.min(block)];
+            let tail = if size > block { &data[(size - block).max(block)..] } else { &[][..] };
+            let expected = md5_of(&[head, tail]);
+            assert_eq!(calculate_partial_hash(&file.0, size as u64, HashAlgorithm::Md5).unwrap(), expected, "size {}", size);
+        }
+    }
+
+    #[test]
+    fn partial_hash_ignores_the_middle_of_large_files() {
+        let block = PARTIAL_HASH_BYTES as usize;
+        let size = 3 * block;
+        let original = contents(size);
+        let mut middle_changed = original.clone();
+        middle_changed[size / 2] ^= 0xff;
+        let mut tail_changed = original.clone();
+        tail_changed[size - 1] ^= 0xff;
+
+        let partial = |name: &str, data: &[u8]| {
+            let file = TestFile::new(name, data);
+            calculate_partial_hash(&file.0, size as u64, HashAlgorithm::Md5).unwrap()
+        };
+        let original = partial("original", &original);
+        assert_eq!(partial("middle", &middle_changed), original);
+        assert_ne!(partial("tail", &tail_changed), original);
+    }
+
+    #[test]
+    fn partial_hash_of_a_file_shorter_than_its_size_fails() {
+        let file = TestFile::new("truncated", &contents(10));
+        assert!(calculate_partial_hash(&file.0, 20, HashAlgorithm::Md5).is_err());
+    }
+
+    #[test]
+    fn strict_hashing_stops_at_the_first_failure() {
+        let pool = ThreadPoolBuilder::new().num_threads(4).build().unwrap();
+        let present = TestFile::new("strict", b"abc");
+        // Every fifth path is missing
+        let paths: Vec<PathBuf> = (0..100)
+            .map(|i| if i % 5 == 3 { PathBuf::from(format!("/nonexistent/dupchecker/{}", i)) } else { present.0.clone() })
+            .collect();
+        let read = |path: &Path| -> Result<usize, Box<dyn std::error::Error>> { Ok(fs::read(path)?.len()) };
+
+        let all = hash_all(&pool, paths.clone(), false, read);
+        assert_eq!(all.len(), 100);
+        assert_eq!(all.iter().filter(|(_, result)| result.is_err()).count(), 20);
+
+        for _ in 0..10 {
+            let strict = hash_all(&pool, paths.clone(), true, read);
+            assert_eq!(strict.len(), 4);
+            assert!(strict[..3].iter().all(|(_, result)| result.is_ok()));
+            assert_eq!(strict[3].0, paths[3]);
+            assert!(strict[3].1.is_err());
+        }
+    }
+
+    #[test]
+    fn scanning_nothing_is_an_error() {
+        assert!(crate::Scanner::new().scan().is_err());
+    }
+
+    #[test]
+    fn reference_groups_keep_one_reference_copy() {
+        let file = |path: &str, reference: bool| FileEntry {
+            path: PathBuf::from(path),
+            stamp: FileStamp { size: 1, modified: None, inode: 0, dev: 0 },
+            reference,
+        };
+        let group = |files: Vec<FileEntry>| DuplicateGroup { hash: "md5:0".to_string(), files };
+        let groups = vec![
+            group(vec![file("/ref/old/a.jpg", true), file("/new/a.jpg", false), file("/ref/a.jpg", true)]),
+            // Only references, or only targets: nothing to do either way
+            group(vec![file("/ref/c.jpg", true), file("/ref/d.jpg", true)]),
+            group(vec![file("/new/c.jpg", false), file("/new/d.jpg", false)]),
+        ];
+
+        let kept = keep_references(groups, &KeepPolicy { rules: vec!["shortest-path".parse().unwrap()] });
+        assert_eq!(kept.len(), 1);
+        let paths: Vec<&Path> = kept[0].files.iter().map(|file| file.path.as_path()).collect();
+        // The keep policy picks among the reference copies only, though a target's path is as short
+        assert_eq!(paths, [Path::new("/ref/a.jpg"), Path::new("/new/a.jpg")]);
+    }
+}
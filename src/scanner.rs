@@ -0,0 +1,156 @@
+use std::path::PathBuf;
+
+use crate::filetype::Detection;
+use crate::hasher::HashAlgorithm;
+use crate::keep::KeepPolicy;
+use crate::perceptual::PerceptualAlgorithm;
+use crate::scan::{find_duplicate_images, HashMode, ScanOptions, ScanReport, default_extensions};
+
+/// Finds duplicate images under a set of folders.
+///
+/// Starts out comparing the exact contents of the default image types by MD5, without a
+/// hash cache, keeping the file whose path sorts first in each group. Each setter replaces
+/// what was set before.
+///
+/// ```no_run
+/// use dupchecker::{HashAlgorithm, Scanner};
+///
+/// let report = Scanner::new().root("photos").hasher(HashAlgorithm::Blake3).scan()?;
+/// for group in &report.groups {
+///     println!("keep {}", group.keep().path.display());
+/// }
+/// # Ok::<(), Box<dyn std::error::Error>>(())
+/// ```
+pub struct Scanner {
+    roots: Vec<PathBuf>,
+    references: Vec<PathBuf>,
+    options: ScanOptions,
+}
+
+fn normalize_extensions<S: AsRef<str>>(extensions: impl IntoIterator<Item = S>) -> Vec<String> {
+    extensions.into_iter().map(|e| e.as_ref().trim_start_matches('.').to_lowercase()).collect()
+}
+
+impl Default for Scanner {
+    fn default() -> Scanner {
+        Scanner::new()
+    }
+}
+
+impl Scanner {
+    /// A scanner with no roots yet and every setting at its default
+    pub fn new() -> Scanner {
+        Scanner {
+            roots: Vec::new(),
+            references: Vec::new(),
+            options: ScanOptions {
+                extensions: default_extensions(),
+                exclude: Vec::new(),
+                detection: Detection::Extension,
+                mode: HashMode::Exact { algorithm: HashAlgorithm::Md5 },
+                strict: false,
+                jobs: 0,
+                cache: None,
+                rehash: false,
+                keep: KeepPolicy::default(),
+            },
+        }
+    }
+
+    /// Add a folder to scan
+    pub fn root(mut self, root: impl Into<PathBuf>) -> Scanner {
+        self.roots.push(root.into());
+        self
+    }
+
+    /// Add several folders to scan
+    pub fn roots<P: Into<PathBuf>>(mut self, roots: impl IntoIterator<Item = P>) -> Scanner {
+        self.roots.extend(roots.into_iter().map(Into::into));
+        self
+    }
+
+    /// Add a read-only folder to check the roots against. Once there is one, only files in the
+    /// roots that duplicate a file in a reference folder are reported, each group keeping a
+    /// reference copy, and nothing in a reference folder is ever offered for removal.
+    pub fn reference(mut self, reference: impl Into<PathBuf>) -> Scanner {
+        self.references.push(reference.into());
+        self
+    }
+
+    /// Add several read-only folders to check the roots against
+    pub fn references<P: Into<PathBuf>>(mut self, references: impl IntoIterator<Item = P>) -> Scanner {
+        self.references.extend(references.into_iter().map(Into::into));
+        self
+    }
+
+    /// Only scan files with these extensions. Case and a leading dot don't matter.
+    /// When detecting by content, only scan the types these are the usual extensions of.
+    pub fn extensions<S: AsRef<str>>(mut self, extensions: impl IntoIterator<Item = S>) -> Scanner {
+        self.options.extensions = normalize_extensions(extensions);
+        self
+    }
+
+    /// Never scan files with these extensions, or when detecting by content, of these types
+    pub fn exclude<S: AsRef<str>>(mut self, extensions: impl IntoIterator<Item = S>) -> Scanner {
+        self.options.exclude = normalize_extensions(extensions);
+        self
+    }
+
+    /// Decide which files are images by their extensions, the default, or by their contents
+    pub fn detection(mut self, detection: Detection) -> Scanner {
+        self.options.detection = detection;
+        self
+    }
+
+    /// Group files whose contents hash identically with this algorithm
+    pub fn hasher(self, algorithm: HashAlgorithm) -> Scanner {
+        self.mode(HashMode::Exact { algorithm })
+    }
+
+    /// Group images whose perceptual hashes differ in at most `threshold` bits, out of 64
+    pub fn perceptual(self, algorithm: PerceptualAlgorithm, threshold: u32) -> Scanner {
+        self.mode(HashMode::Perceptual { algorithm, threshold })
+    }
+
+    /// Compare files this way, which [`Scanner::hasher`] and [`Scanner::perceptual`] are shorthands for
+    pub fn mode(mut self, mode: HashMode) -> Scanner {
+        self.options.mode = mode;
+        self
+    }
+
+    /// Fail on the first file that can't be read or decoded, instead of skipping it
+    pub fn strict(mut self, strict: bool) -> Scanner {
+        self.options.strict = strict;
+        self
+    }
+
+    /// Hash this many files at once. 0, the default, means one per CPU core.
+    pub fn jobs(mut self, jobs: usize) -> Scanner {
+        self.options.jobs = jobs;
+        self
+    }
+
+    /// Keep hashes in this SQLite database between scans, so unchanged files aren't read again
+    pub fn cache(mut self, cache: impl Into<PathBuf>) -> Scanner {
+        self.options.cache = Some(cache.into());
+        self
+    }
+
+    /// Hash every file again instead of trusting the cache, still storing the fresh hashes
+    pub fn rehash(mut self, rehash: bool) -> Scanner {
+        self.options.rehash = rehash;
+        self
+    }
+
+    /// Decide which file in each group is kept
+    pub fn keep(mut self, keep: KeepPolicy) -> Scanner {
+        self.options.keep = keep;
+        self
+    }
+
+    /// Walk the roots and group the duplicates found. Fails if there are no roots or one
+    /// isn't a folder. Prints nothing: files skipped and other warnings are in the report.
+    pub fn scan(&self) -> Result<ScanReport, Box<dyn std::error::Error>> {
+        find_duplicate_images(&self.roots, &self.references, &self.options)
+    }
+}
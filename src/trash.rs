@@ -0,0 +1,246 @@
+#[cfg(unix)]
+use std::ffi::OsString;
+use std::fs;
+#[cfg(unix)]
+use std::fs::{DirBuilder, OpenOptions};
+#[cfg(unix)]
+use std::io::Write;
+use std::io::{self, ErrorKind};
+#[cfg(unix)]
+use std::os::unix::fs::{DirBuilderExt, MetadataExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+
+use crate::actions::move_file;
+#[cfg(unix)]
+use crate::actions::parent_dir;
+
+// The user's own trash, $XDG_DATA_HOME/Trash
+#[cfg(unix)]
+fn home_trash() -> io::Result<PathBuf> {
+    let data_home = match std::env::var_os("XDG_DATA_HOME") {
+        Some(dir) if !dir.is_empty() => PathBuf::from(dir),
+        _ => match std::env::var_os("HOME") {
+            Some(home) => PathBuf::from(home).join(".local").join("share"),
+            None => return Err(io::Error::new(ErrorKind::NotFound, "HOME is not set")),
+        },
+    };
+    Ok(data_home.join("Trash"))
+}
+
+// The top directory of the mount a path lives on: the highest ancestor still on the same device
+#[cfg(unix)]
+fn mount_top(path: &Path, device: u64) -> PathBuf {
+    let mut top = path.to_path_buf();
+    for ancestor in path.ancestors().skip(1) {
+        match fs::metadata(ancestor) {
+            Ok(metadata) if metadata.dev() == device => top = ancestor.to_path_buf(),
+            _ => break,
+        }
+    }
+    top
+}
+
+// The trash to use on a mount other than the home trash's: the shared $topdir/.Trash/$uid
+// when the administrator has set one up properly, otherwise the per-user $topdir/.Trash-$uid
+#[cfg(unix)]
+fn mount_trash(top: &Path, uid: u32) -> io::Result<PathBuf> {
+    let shared = top.join(".Trash");
+    if let Ok(metadata) = fs::symlink_metadata(&shared) {
+        // The spec requires a real directory with the sticky bit set, anything else is ignored
+        let sticky = metadata.permissions().mode() & 0o1000 != 0;
+        if metadata.is_dir() && !metadata.file_type().is_symlink() && sticky {
+            let user_trash = shared.join(uid.to_string());
+            if ensure_private_dir(&user_trash).is_ok() {
+                return Ok(user_trash);
+            }
+        }
+    }
+
+    let user_trash = top.join(format!(".Trash-{}", uid));
+    ensure_private_dir(&user_trash)?;
+    Ok(user_trash)
+}
+
+// Create a directory readable only by the user, or check an existing one isn't a symlink
+#[cfg(unix)]
+fn ensure_private_dir(dir: &Path) -> io::Result<()> {
+    match DirBuilder::new().recursive(true).mode(0o700).create(dir) {
+        Ok(()) => {}
+        Err(e) if e.kind() == ErrorKind::AlreadyExists => {}
+        Err(e) => return Err(e),
+    }
+    let metadata = fs::symlink_metadata(dir)?;
+    if !metadata.is_dir() {
+        return Err(io::Error::other(format!("{} is not a directory", dir.display())));
+    }
+    Ok(())
+}
+
+// Percent-encode a path for the Path= key, as the spec asks for
+#[cfg(unix)]
+fn encode_path(path: &Path) -> String {
+    let mut encoded = String::new();
+    for &byte in path.as_os_str().as_encoded_bytes() {
+        if byte.is_ascii_alphanumeric() || b"/-_.~".contains(&byte) {
+            encoded.push(byte as char);
+        } else {
+            encoded.push_str(&format!("%{:02X}", byte));
+        }
+    }
+    encoded
+}
+
+// The current local time as YYYY-MM-DDThh:mm:ss, the format DeletionDate= uses
+#[cfg(unix)]
+pub fn local_time() -> String {
+    // SAFETY: time(NULL) only reads the clock, and localtime_r writes into the tm we own
+    unsafe {
+        let now = libc::time(std::ptr::null_mut());
+        let mut tm: libc::tm = std::mem::zeroed();
+        libc::localtime_r(&now, &mut tm);
+        format!(
+            "{:04}-{:02}-{:02}T{:02}:{:02}:{:02}",
+            tm.tm_year + 1900,
+            tm.tm_mon + 1,
+            tm.tm_mday,
+            tm.tm_hour,
+            tm.tm_min,
+            tm.tm_sec
+        )
+    }
+}
+
+// Without localtime_r the time is given in UTC, in the same format
+#[cfg(not(unix))]
+pub fn local_time() -> String {
+    let now = crate::report::format_timestamp(std::time::SystemTime::now()).unwrap_or_default();
+    now.chars().take("YYYY-MM-DDThh:mm:ss".len()).collect()
+}
+
+// Claim a name in info/ by creating its .trashinfo exclusively, so two processes trashing
+// files with the same name at once can never both get it
+#[cfg(unix)]
+fn reserve_name(trash: &Path, path: &Path, original: &str) -> io::Result<(OsString, PathBuf)> {
+    let file_name = path.file_name().ok_or_else(|| io::Error::other("path has no file name"))?;
+    let stem = Path::new(file_name).file_stem().unwrap_or(file_name).to_os_string();
+    let extension = Path::new(file_name).extension().map(|e| e.to_os_string());
+
+    let info = format!("[Trash Info]\nPath={}\nDeletionDate={}\n", original, local_time());
+    for attempt in 0..10_000 {
+        let mut name = stem.clone();
+        if attempt > 0 {
+            name.push(format!(".{}", attempt));
+        }
+        if let Some(extension) = &extension {
+            name.push(".");
+            name.push(extension);
+        }
+        if fs::symlink_metadata(trash.join("files").join(&name)).is_ok() {
+            continue;
+        }
+
+        let mut info_name = name.clone();
+        info_name.push(".trashinfo");
+        let info_path = trash.join("info").join(info_name);
+        match OpenOptions::new().write(true).create_new(true).open(&info_path) {
+            Ok(mut info_file) => {
+                info_file.write_all(info.as_bytes())?;
+                return Ok((name, info_path));
+            }
+            Err(e) if e.kind() == ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::new(ErrorKind::AlreadyExists, "no free name left in the trash"))
+}
+
+// Move a file to the freedesktop.org trash for the mount it lives on, so it can be restored
+// from the desktop's file manager. Files are only ever renamed into a trash on their own
+// filesystem, never copied. Returns where the file now is, under the trash's files/ directory.
+#[cfg(unix)]
+pub fn trash_file(path: &Path) -> io::Result<PathBuf> {
+    trash_file_with_home(path, home_trash()?)
+}
+
+// trash_file, with the home trash given rather than looked up
+#[cfg(unix)]
+fn trash_file_with_home(path: &Path, home: PathBuf) -> io::Result<PathBuf> {
+    let path = fs::canonicalize(parent_dir(path))?
+        .join(path.file_name().ok_or_else(|| io::Error::other("path has no file name"))?);
+    let device = fs::symlink_metadata(&path)?.dev();
+    // SAFETY: getuid can't fail and has no side effects
+    let uid = unsafe { libc::getuid() };
+
+    // Use the home trash when it's on the same device, otherwise the trash at the top of the mount.
+    // Home trash entries record the absolute path, mount trash entries the path from the top.
+    let home_device = home.ancestors().find_map(|dir| fs::metadata(dir).ok()).map(|metadata| metadata.dev());
+    let (trash, original) = if home_device == Some(device) {
+        (home, encode_path(&path))
+    } else {
+        let top = mount_top(&path, device);
+        let relative = path.strip_prefix(&top).unwrap_or(&path);
+        (mount_trash(&top, uid)?, encode_path(relative))
+    };
+    ensure_private_dir(&trash.join("files"))?;
+    ensure_private_dir(&trash.join("info"))?;
+
+    let (name, info_path) = reserve_name(&trash, &path, &original)?;
+    let files_path = trash.join("files").join(name);
+    if let Err(e) = fs::rename(&path, &files_path) {
+        // Don't leave an info record behind for a file that never made it to the trash
+        let _ = fs::remove_file(&info_path);
+        return Err(e);
+    }
+    Ok(files_path)
+}
+
+#[cfg(not(unix))]
+pub fn trash_file(_path: &Path) -> io::Result<PathBuf> {
+    Err(io::Error::new(ErrorKind::Unsupported, "the trash is only supported on Unix-like systems"))
+}
+
+// Move a file out of the trash back to `original`, and drop the .trashinfo record that
+// would otherwise still offer it for restoring
+pub fn restore_file(files_path: &Path, original: &Path) -> io::Result<()> {
+    move_file(files_path, original)?;
+    if let (Some(trash), Some(name)) = (files_path.parent().and_then(Path::parent), files_path.file_name()) {
+        let mut info_name = name.to_os_string();
+        info_name.push(".trashinfo");
+        let _ = fs::remove_file(trash.join("info").join(info_name));
+    }
+    Ok(())
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    #[test]
+    fn trashed_files_never_share_a_name() {
+        let dir = TempDir::new();
+        let trash = dir.path().join("Trash");
+        let first = dir.write("a/photo 1.jpg", b"first");
+        let second = dir.write("b/photo 1.jpg", b"second");
+
+        let first_trashed = trash_file_with_home(&first, trash.clone()).unwrap();
+        let second_trashed = trash_file_with_home(&second, trash.clone()).unwrap();
+        assert_eq!(first_trashed, fs::canonicalize(&trash).unwrap().join("files/photo 1.jpg"));
+        assert_eq!(second_trashed.file_name().unwrap(), "photo 1.1.jpg");
+        assert!(!first.exists() && !second.exists());
+        let info = fs::read_to_string(trash.join("info/photo 1.jpg.trashinfo")).unwrap();
+        let original = encode_path(&fs::canonicalize(dir.path()).unwrap().join("a/photo 1.jpg"));
+        assert!(info.starts_with(&format!("[Trash Info]\nPath={}\nDeletionDate=", original)));
+        assert!(original.ends_with("/a/photo%201.jpg"));
+
+        // A name is taken as soon as either its file or its info record exists
+        fs::remove_file(trash.join("info/photo 1.1.jpg.trashinfo")).unwrap();
+        fs::write(trash.join("info/photo 1.2.jpg.trashinfo"), b"").unwrap();
+        let (name, _) = reserve_name(&trash, Path::new("photo 1.jpg"), "photo%201.jpg").unwrap();
+        assert_eq!(name, "photo 1.3.jpg");
+
+        restore_file(&first_trashed, &first).unwrap();
+        assert_eq!(fs::read(&first).unwrap(), b"first");
+        assert!(!trash.join("info/photo 1.jpg.trashinfo").exists());
+    }
+}
@@ -0,0 +1,102 @@
+use std::fs::File;
+use std::io::{self, ErrorKind, Read};
+use std::path::Path;
+
+use crate::escape::escape_path;
+use crate::plan::PlannedFile;
+use crate::scan::FileStamp;
+
+// Size of the buffers the two files are compared through
+const COMPARE_BUFFER_BYTES: usize = 64 * 1024;
+
+// Fill as much of the buffer as the file has left, returning how much was read
+fn read_full(file: &mut File, buffer: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buffer.len() {
+        match file.read(&mut buffer[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(filled)
+}
+
+// Compare two files byte by byte
+pub fn same_contents(a: &Path, b: &Path) -> io::Result<bool> {
+    let mut file_a = File::open(a)?;
+    let mut file_b = File::open(b)?;
+    if file_a.metadata()?.len() != file_b.metadata()?.len() {
+        return Ok(false);
+    }
+
+    let mut buffer_a = vec![0u8; COMPARE_BUFFER_BYTES];
+    let mut buffer_b = vec![0u8; COMPARE_BUFFER_BYTES];
+    loop {
+        let read_a = read_full(&mut file_a, &mut buffer_a)?;
+        let read_b = read_full(&mut file_b, &mut buffer_b)?;
+        if read_a != read_b || buffer_a[..read_a] != buffer_b[..read_b] {
+            return Ok(false);
+        }
+        if read_a == 0 {
+            return Ok(true);
+        }
+    }
+}
+
+// Check that a file still looks the way it did when it was hashed
+fn check_unchanged(file: &PlannedFile) -> Result<(), String> {
+    let current = FileStamp::of(&file.path).map_err(|e| format!("cannot stat {}: {}", escape_path(&file.path), e))?;
+    if current != file.stamp {
+        return Err(format!("{} changed since it was hashed", escape_path(&file.path)));
+    }
+    Ok(())
+}
+
+// Make sure a duplicate can be removed without losing anything the kept file doesn't have.
+// Both files must be unchanged since the scan, and with `compare_contents` they must also
+// be identical byte for byte, so a hash collision can never cost a file.
+pub fn verify_duplicate(keep: &PlannedFile, duplicate: &PlannedFile, compare_contents: bool) -> Result<(), String> {
+    check_unchanged(keep)?;
+    check_unchanged(duplicate)?;
+
+    if compare_contents {
+        match same_contents(&keep.path, &duplicate.path) {
+            Ok(true) => {}
+            Ok(false) => return Err(format!("contents differ from {}", escape_path(&keep.path))),
+            Err(e) => return Err(format!("cannot compare with {}: {}", escape_path(&keep.path), e)),
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::testing::TempDir;
+
+    fn planned(path: std::path::PathBuf) -> PlannedFile {
+        let stamp = FileStamp::of(&path).unwrap();
+        PlannedFile { path, stamp }
+    }
+
+    #[test]
+    fn duplicates_must_be_unchanged_and_identical() {
+        let dir = TempDir::new();
+        let keep = planned(dir.write("keep.jpg", b"same bytes"));
+        let copy = planned(dir.write("copy.jpg", b"same bytes"));
+        let collision = planned(dir.write("collision.jpg", b"other byte"));
+        assert!(verify_duplicate(&keep, &copy, true).is_ok());
+
+        // Same size and hash, but not the same file
+        assert!(verify_duplicate(&keep, &collision, true).unwrap_err().contains("contents differ"));
+        assert!(verify_duplicate(&keep, &collision, false).is_ok());
+
+        // Either file changing since the scan stops the removal, contents compared or not
+        std::fs::write(&copy.path, b"edited since").unwrap();
+        assert!(verify_duplicate(&keep, &copy, false).unwrap_err().contains("changed since it was hashed"));
+        std::fs::remove_file(&keep.path).unwrap();
+        assert!(verify_duplicate(&keep, &collision, false).unwrap_err().contains("cannot stat"));
+    }
+}